@@ -2,12 +2,28 @@
 use std::str::FromStr;
 use std::convert::Into;
 use std::f64::consts::PI;
-use crate::units::{RadPS, MAXRADPS, Hz, MAXHZ};
+use crate::units::{RadPS, MAXRADPS, Hz, SAMPLE_RATE, MAXHZ};
+use crate::simple::{blep, SubOsc, SyncIn};
 use crate::module::*;
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum Function{ SIN, TRI, SAWUP, SAWDOWN, SQUARE }
 
+// names of the functions, as parsed from config files
+pub const FUNCTIONS: &[&str] = &["sin", "tri", "sawup", "sawdown", "square"];
+
+impl Function {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::SIN => "sin",
+            Function::TRI => "tri",
+            Function::SAWUP => "sawup",
+            Function::SAWDOWN => "sawdown",
+            Function::SQUARE => "square",
+        }
+    }
+}
+
 impl Default for Function {
     fn default() -> Self { Function::SIN }
 }
@@ -35,7 +51,7 @@ fn powneg1(k: usize) -> f64 {
     (-1.0_f64).powf(k as f64)
 }
 
-fn get_series(func: Function, n: usize) -> Vec<HarmonicParam> {
+pub fn get_series(func: Function, n: usize) -> Vec<HarmonicParam> {
     match func {
         Function::SIN => vec![HarmonicParam{ k: 1, amp: 1.0 }],
         Function::SAWUP => (1..=n).map(|k|
@@ -58,14 +74,34 @@ fn get_series(func: Function, n: usize) -> Vec<HarmonicParam> {
 // An additive generator generates a signal as a sum of SIN waves.
 pub struct Gen {
     pub series: Vec<HarmonicParam>,
+    func: Function,
+    order: usize,
     phase: f64, // in radians, invariant: 0 <= phase < 2*PI
     velocity: RadPS, // invariant: 0 <= velocity <= PI
+    rate: f64, // sampling rate that velocity is relative to
+    offset: f64, // fraction of a cycle added to the phase
+    sync: SyncIn,
+    sub: SubOsc,
 
     val: f64,
 }
 
 #[allow(dead_code)]
 impl Gen {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("osc", "source", "band-limited oscillator summing sine harmonics")
+            .arg(ArgDescr::new("functype", ArgType::choice(FUNCTIONS)).descr("wave shape"))
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("initial frequency"))
+            .arg(ArgDescr::new("order", ArgType::Int).range(1.0, f64::INFINITY).descr("number of harmonics"))
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("oscillator frequency"))
+            .input(TerminalDescr::new("sync", Unit::Gate).default(0.0).descr("start the cycle over when it goes high"))
+            .input(TerminalDescr::new("phase", Unit::Value).range(0.0, 1.0).default(0.0).descr("fraction of a cycle added to the phase of out"))
+            .output(TerminalDescr::new("out", Unit::Audio).range(-1.0, 1.0))
+            .output(TerminalDescr::new("subosc1", Unit::Audio).range(-1.0, 1.0).descr("square wave one octave down"))
+            .output(TerminalDescr::new("subosc2", Unit::Audio).range(-1.0, 1.0).descr("square wave two octaves down"))
+            .output(TerminalDescr::new("eoc", Unit::Gate).descr("high for a sample when a cycle ends"))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 4 {
             return Err(format!("usage: {} functype freq order", args[0]));
@@ -80,17 +116,32 @@ impl Gen {
         Self {
             phase: 0.0,
             velocity: freq.into(),
+            rate: SAMPLE_RATE,
             series: get_series(typ, n),
+            func: typ,
+            order: n,
+            offset: 0.0,
+            sync: SyncIn::default(),
+            sub: SubOsc::default(),
             val: 0.0
         }
     }
 
+    // freq is relative to the default sampling rate, like the constructor's.
     pub fn set_freq(&mut self, freq: impl Into<RadPS>) {
-        self.velocity = freq.into();
+        let RadPS(w) = freq.into();
+        self.velocity = RadPS((w * SAMPLE_RATE / self.rate).min(MAXRADPS));
+    }
+
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        self.velocity = RadPS((self.velocity.0 * self.rate / rate).min(MAXRADPS));
+        self.rate = rate;
     }
 
     pub fn set_func(&mut self, typ: Function, n: usize) {
         self.series = get_series(typ, n);
+        self.func = typ;
+        self.order = n;
     }
 
     pub fn set_phase(&mut self, theta: f64) {
@@ -108,34 +159,97 @@ impl Gen {
         cost
     }
 
-    pub fn advance(&mut self) -> f64 {
-        self.phase = (self.phase + self.velocity.0) % (2.0 * PI);
+    // Fraction of a cycle added to the phase, for the phase input.
+    pub fn set_offset(&mut self, offset: f64) {
+        self.offset = offset;
+    }
+
+    // Start the cycle over on the next sample, for hard sync.
+    pub fn sync(&mut self) {
+        self.sync.trigger();
+    }
 
+    // Sum of the harmonics at a phase, with the offset of the phase input.
+    fn value_at(&self, phase: f64) -> f64 {
+        let phase = if self.offset == 0.0 { phase } else { (phase + 2.0 * PI * self.offset) % (2.0 * PI) };
         let mut x = 0.0;
         for param in self.series.iter() {
             if param.k as f64 * self.velocity.0 <= MAXRADPS { // disallow aliasing
-                x += param.amp * (param.k as f64 * self.phase).sin();
+                x += param.amp * (param.k as f64 * phase).sin();
             }
         }
-        self.val = x;
+        x
+    }
+
+    pub fn advance(&mut self) -> f64 {
+        let next = self.phase + self.velocity.0;
+        let synced = self.sync.take();
+        self.sub.advance(next >= 2.0 * PI, synced.is_some());
+        self.val = match synced {
+            Some(since) => {
+                // the cycle starts over between samples, where the sync went high,
+                // and the jump there is smoothed like osc2's blep waves
+                let before = self.value_at((self.phase + (1.0 - since) * self.velocity.0) % (2.0 * PI));
+                self.phase = since * self.velocity.0;
+                let dt = self.velocity.0 / (2.0 * PI);
+                self.value_at(self.phase) + (self.value_at(0.0) - before) * blep(since * dt, dt)
+            },
+            None => {
+                self.phase = next % (2.0 * PI);
+                self.value_at(self.phase)
+            },
+        };
         self.val
     }
+
+    // Sub-oscillators are smoothed like osc2's blep waves, as they aren't sums of harmonics.
+    pub fn subosc(&self, octaves: u32) -> f64 {
+        self.sub.get(octaves, self.phase / (2.0 * PI), self.velocity.0 / (2.0 * PI), true)
+    }
+
+    pub fn eoc(&self) -> f64 {
+        self.sub.eoc()
+    }
 }
 
 impl Module for Gen {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["freq".to_string()],
-         vec!["out".to_string()])
+        let (mut ins, outs) = Gen::descr().terminals();
+        ins[0].max = self.rate / 2.0;
+        ins[0].default = self.velocity.to_hz(self.rate).0;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["osc".to_string(), self.func.name().to_string(),
+             self.velocity.to_hz(self.rate).0.to_string(), self.order.to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
-        if idx == 0 { return Some(self.val); }
-        None
+        match idx {
+            0 => Some(self.val),
+            1 => Some(self.subosc(1)),
+            2 => Some(self.subosc(2)),
+            3 => Some(self.eoc()),
+            _ => None,
+        }
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        match idx {
+            0 => Some(self.velocity.to_hz(self.rate).0),
+            1 => Some(self.sync.value()),
+            2 => Some(self.offset),
+            _ => None,
+        }
     }
 
     fn set_input(&mut self, idx: usize, value: f64) {
-        if idx == 0 {
-            self.velocity = Hz(value.clamp(0.0, MAXHZ)).into();
+        match idx {
+            0 => self.velocity = Hz(value).to_radps(self.rate),
+            1 => self.sync.set(value),
+            2 => self.set_offset(value),
+            _ => (),
         }
     }
 
@@ -143,8 +257,32 @@ impl Module for Gen {
         Gen::advance(self);
         true
     }
+
+    fn process_block(&mut self, len: usize, inputs: &[&[f64]], outputs: &mut [&mut [f64]],
+                     _events: &[Timed], _out_events: &mut Vec<Timed>) -> bool {
+        let [out, sub1, sub2, eoc] = outputs else {
+            unreachable!("osc outputs are out, subosc1, subosc2 and eoc");
+        };
+        let outs = out.iter_mut().zip(sub1.iter_mut()).zip(sub2.iter_mut()).zip(eoc.iter_mut());
+        for (n, (((out, sub1), sub2), eoc)) in outs.take(len).enumerate() {
+            for (idx, inp) in inputs.iter().enumerate() {
+                if let Some(&v) = inp.get(n) {
+                    self.set_input(idx, v);
+                }
+            }
+            *out = Gen::advance(self);
+            *sub1 = self.subosc(1);
+            *sub2 = self.subosc(2);
+            *eoc = self.eoc();
+        }
+        true
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        Gen::set_sample_rate(self, rate);
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("osc", Gen::from_cmd);
+    l.register(Gen::descr(), Gen::from_cmd);
 }
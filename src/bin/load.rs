@@ -1,17 +1,136 @@
+
 /*
  * Load a synth from a config file and run it.
  */
 
 use std::env;
+use std::fs;
+use std::time::SystemTime;
 use rau::loader;
+use rau::console::Console;
+use rau::osc;
+use rau::module::{parse, Rack};
 use rau::units::Samples;
 
+// Modification times of files, None for files that can't be read.
+fn mtimes(fnames: &[String]) -> Vec<Option<SystemTime>> {
+    fnames.iter().map(|f| fs::metadata(f).and_then(|m| m.modified()).ok()).collect()
+}
+
+fn show_diagnostics(rack: &Rack) {
+    for diag in rack.validate() {
+        println!("{}", diag);
+    }
+}
+
+// Run the rack until a module or the console asks to stop, running console
+// commands and OSC messages between blocks.
+// When watching, the config is loaded again whenever it or a file it includes changes.
+// Unchanged modules keep playing as they were, and a config with errors is
+// reported and ignored until it is fixed.
+fn play(l: &mut loader::Loader, fname: &str, mut rack: Rack, watching: bool, console: Option<Console>, server: Option<osc::Server>) {
+    let check_every = (rack.sample_rate() / 4.0) as usize; // samples
+    let mut files = l.files().to_vec();
+    let mut stamps = mtimes(&files);
+    let mut samples = 0;
+    while rack.run(Samples(128)) {
+        if let Some(ref c) = console {
+            if !c.poll(l, &mut rack) {
+                break;
+            }
+        }
+        if let Some(ref s) = server {
+            s.poll(&mut rack);
+        }
+        if !watching {
+            continue;
+        }
+
+        samples += 128;
+        if samples < check_every {
+            continue;
+        }
+        samples = 0;
+
+        let now = mtimes(&files);
+        if now == stamps {
+            continue;
+        }
+        match l.reload(&rack, fname) {
+            Ok(new_rack) => {
+                println!("reloaded {}", fname);
+                show_diagnostics(&new_rack);
+                rack = new_rack;
+            },
+            Err(e) => println!("{}", e),
+        }
+        // the files may be different after an include changed
+        files = l.files().to_vec();
+        stamps = mtimes(&files);
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
     let mut l = loader::Loader::new();
 
+    if args.len() == 2 && args[1] == "--json" {
+        println!("{}", l.to_json());
+        return;
+    }
+
+    if args.len() == 3 && args[1] == "--describe" {
+        match l.describe(&args[2]) {
+            Ok(descr) => print!("{}", descr.help()),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+
+    if args.len() == 3 && args[1] == "--graph" {
+        // don't open the audio device just to draw the patch
+        l.capture_speakers();
+        match l.load(&args[2]) {
+            Ok(rack) => print!("{}", rack.to_dot()),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+
+    let mut watching = false;
+    let mut use_console = false;
+    let mut server = None;
+    while args.len() > 2 {
+        if args.len() > 3 && args[1] == "--rate" {
+            match parse::<f64>("rate", &args[2]) {
+                Ok(rate) => l.set_sample_rate(rate),
+                Err(e) => { println!("{}", e); return; },
+            }
+            args.drain(1..3);
+        } else if args[1] == "--watch" {
+            watching = true;
+            args.remove(1);
+        } else if args[1] == "--console" {
+            use_console = true;
+            args.remove(1);
+        } else if args.len() > 3 && args[1] == "--osc" {
+            // a port alone only listens on this machine
+            let addr = if args[2].contains(':') { args[2].clone() } else { format!("127.0.0.1:{}", args[2]) };
+            match osc::Server::bind(&addr) {
+                Ok(s) => server = Some(s),
+                Err(e) => { println!("{}", e); return; },
+            }
+            args.drain(1..3);
+        } else {
+            break;
+        }
+    }
+
     if args.len() != 2 {
-        println!("usage: {} fname", args[0]);
+        println!("usage: {} [--rate hz] [--watch] [--console] [--osc [addr:]port] fname", args[0]);
+        println!("       {} --graph fname", args[0]);
+        println!("       {} --describe module", args[0]);
+        println!("       {} --json", args[0]);
         println!("modules:");
         l.show_usage();
         return;
@@ -20,10 +139,10 @@ fn main() {
 
     match l.load(fname) {
         Err(e) => println!("{}", e),
-        Ok(mut rack) => {
-            while rack.run(Samples(128)) {
-                continue;
-            }
+        Ok(rack) => {
+            show_diagnostics(&rack);
+            let console = if use_console { Some(Console::start()) } else { None };
+            play(&mut l, fname, rack, watching, console, server);
         },
     };
 }
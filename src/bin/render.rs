@@ -0,0 +1,39 @@
+
+/*
+ * Render a synth config file to a wav file, faster than real time.
+ */
+
+use std::env;
+use rau::loader;
+use rau::render::render;
+use rau::units::{Sec, SAMPLE_RATE};
+use rau::module::parse;
+use rau::wav::{write_wav, WavFormat};
+
+fn run(args: &[String]) -> Result<(), String> {
+    let fname = &args[1];
+    let outname = &args[2];
+    let secs = parse::<f64>("seconds", &args[3])?;
+    let fmt = if args.len() > 4 { parse::<WavFormat>("format", &args[4])? } else { WavFormat::S16 };
+    let rate = if args.len() > 5 { parse::<f64>("rate", &args[5])? } else { SAMPLE_RATE };
+
+    let mut l = loader::Loader::new();
+    l.set_sample_rate(rate);
+    let rec = l.capture_speakers();
+    let mut rack = l.load(fname)?;
+    let samps = render(&mut rack, &rec, Sec(secs).to_samples(rate));
+    write_wav(outname, rate as u32, &samps, fmt)?;
+    println!("wrote {} samples to {}", samps.len(), outname);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 || args.len() > 6 {
+        println!("usage: {} fname out.wav seconds [16|24|32f [rate]]", args[0]);
+        return;
+    }
+    if let Err(e) = run(&args) {
+        println!("{}", e);
+    }
+}
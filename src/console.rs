@@ -0,0 +1,259 @@
+/*
+ * A command console for inspecting and changing a rack while it plays.
+ * Lines are read and parsed on their own thread, which also reads the files
+ * they include and writes the files they save, so that the thread running the
+ * rack is only handed the changes to make between blocks of samples.
+ * Modules can't move between threads, so config lines are still turned into
+ * modules on the thread running the rack.
+ */
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use crate::module::*;
+
+pub const HELP: &str = "commands:
+  ls                     list modules
+  wires                  list wires
+  get module:terminal    show the value of an output or input
+  set module:input val   set an input that isn't wired
+  rm module              remove a module and its wires
+  rm module:out module:in  remove a wire
+  save fname             save the rack as a config file
+  help                   show this help
+  quit                   stop playing
+any other line is run as a line of a config file, such as a module or wire line";
+
+// A console command, parsed on the console thread.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    List,
+    Wires,
+    Get(String, String), // module, terminal
+    Remove(String),
+    RemoveWire(String, String, String, String), // module, output, module, input
+    Save(String), // file name, written on the console thread from the config text the rack gives
+    Config(String, Vec<(String, PathBuf, String)>), // a config line, and the name, canonical path and text of files it includes
+    Quit,
+}
+
+fn parse_terminal<'a>(what: &str, term: &'a str) -> Result<(&'a str, &'a str), String> {
+    term.split_once(':').ok_or(format!("expected module:{}, not '{}'", what, term))
+}
+
+// Read the files that a config line includes, and the files they include in turn.
+// Files that can't be read here, such as ones named with variables, are left
+// for the loader to report.
+fn read_includes(fname: &str, text: &str, files: &mut Vec<(String, PathBuf, String)>) {
+    for line in text.lines() {
+        let ws: Vec<&str> = line.split_whitespace().collect();
+        let name = match ws.as_slice() {
+            ["include", name, ..] => name,
+            _ => continue,
+        };
+        // relative to the including file, as the loader does it
+        let path = Path::new(fname).parent().unwrap_or(Path::new("")).join(name);
+        let canon = match path.canonicalize() {
+            Ok(canon) => canon,
+            Err(_) => continue,
+        };
+        if files.iter().any(|(_, c, _)| *c == canon) {
+            continue; // already read, or an include loop for the loader to report
+        }
+        if let Ok(text) = fs::read_to_string(&path) {
+            let fname = path.to_string_lossy().into_owned();
+            files.push((fname.clone(), canon, text.clone()));
+            read_includes(&fname, &text, files);
+        }
+    }
+}
+
+// Turn a line typed at the console into a command for the rack, or None if
+// there is nothing for the rack to do.
+pub fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let ws: Vec<&str> = line.split_whitespace().collect();
+    let cmd = match ws.as_slice() {
+        [] | ["help"] => return Ok(None),
+        ["ls"] => Command::List,
+        ["wires"] => Command::Wires,
+        ["get", term] => {
+            let (m, t) = parse_terminal("terminal", term)?;
+            Command::Get(m.to_owned(), t.to_owned())
+        },
+        ["rm", name] => Command::Remove(name.to_string()),
+        ["rm", from, to] => {
+            let (from_mod, out) = parse_terminal("output", from)?;
+            let (to_mod, inp) = parse_terminal("input", to)?;
+            Command::RemoveWire(from_mod.to_owned(), out.to_owned(), to_mod.to_owned(), inp.to_owned())
+        },
+        ["save", fname] => Command::Save(fname.to_string()),
+        ["quit"] => Command::Quit,
+        _ => {
+            let mut files = Vec::new();
+            read_includes("console", line, &mut files);
+            Command::Config(line.to_owned(), files)
+        },
+    };
+    Ok(Some(cmd))
+}
+
+// Value of an output, or of an input if there is no output with that name.
+fn get_value(rack: &mut Rack, mod_name: &str, name: &str) -> Result<f64, String> {
+    rack.module(mod_name)?;
+    rack.get_output(mod_name, name)
+        .or_else(|_| rack.get_input(mod_name, name))
+        .map_err(|_| format!("{} has no terminal named {}", mod_name, name))
+}
+
+// Run a parsed command against the rack and return the text to show.
+pub fn run(l: &mut Loader, rack: &mut Rack, cmd: Command) -> Result<String, String> {
+    match cmd {
+        Command::List => {
+            let lines: Vec<String> = rack.module_names().iter()
+                .map(|name| format!("{} {}", name, rack.module(name).unwrap().borrow().get_args().join(" ")))
+                .collect();
+            Ok(lines.join("\n"))
+        },
+        Command::Wires => Ok(rack.wire_list().join("\n")),
+        Command::Get(m, t) => Ok(format!("{}", get_value(rack, &m, &t)?)),
+        Command::Remove(name) => rack.remove_module(&name).map(|_| String::new()),
+        Command::RemoveWire(from_mod, out, to_mod, inp) => rack.remove_wire(&from_mod, &out, &to_mod, &inp).map(|_| String::new()),
+        Command::Save(_) => Ok(rack.to_rau()),
+        Command::Config(line, files) => {
+            for (fname, canon, text) in files {
+                l.preload(&fname, canon, text);
+            }
+            l.exec_line(rack, &line).map(|_| String::new())
+        },
+        Command::Quit => Ok(String::new()),
+    }
+}
+
+// Show the answer to a command, writing out the rack's text for a save.
+fn show(save_to: Option<String>, reply: Result<String, String>) {
+    let reply = match (save_to, reply) {
+        (Some(fname), Ok(text)) => fs::write(&fname, text)
+            .map(|_| format!("saved {}", fname))
+            .map_err(|e| format!("{}: {}", fname, e)),
+        (_, reply) => reply,
+    };
+    match reply {
+        Ok(reply) if reply.is_empty() => (),
+        Ok(reply) => println!("{}", reply),
+        Err(e) => println!("error: {}", e),
+    }
+}
+
+pub struct Console {
+    cmds: Receiver<Command>,
+    replies: Sender<Result<String, String>>,
+}
+
+impl Console {
+    // Start reading commands from stdin.
+    pub fn start() -> Self {
+        let (cmd_tx, cmds) = channel();
+        let (replies, reply_rx) = channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            loop {
+                print!("> ");
+                io::stdout().flush().ok();
+                let mut line = String::new();
+                match stdin.lock().read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => (),
+                }
+                let cmd = match parse_command(&line) {
+                    Ok(Some(cmd)) => cmd,
+                    Ok(None) => {
+                        if line.trim() == "help" {
+                            println!("{}", HELP);
+                        }
+                        continue;
+                    },
+                    Err(e) => {
+                        println!("error: {}", e);
+                        continue;
+                    },
+                };
+                let quit = cmd == Command::Quit;
+                let save_to = match &cmd {
+                    Command::Save(fname) => Some(fname.clone()),
+                    _ => None,
+                };
+                if cmd_tx.send(cmd).is_err() || quit {
+                    break;
+                }
+                // wait for the command to finish so its output comes before the next prompt
+                match reply_rx.recv() {
+                    Ok(reply) => show(save_to, reply),
+                    Err(_) => break,
+                }
+            }
+        });
+        Console{ cmds, replies }
+    }
+
+    // Run any commands that arrived since the last poll, without waiting for more.
+    // Returns false when asked to quit.
+    pub fn poll(&self, l: &mut Loader, rack: &mut Rack) -> bool {
+        while let Ok(cmd) = self.cmds.try_recv() {
+            if cmd == Command::Quit {
+                return false;
+            }
+            let _ = self.replies.send(run(l, rack, cmd));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn includes_are_read_on_the_console_thread() {
+        let dir = env::temp_dir().join("rau_console_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.rau"), "include b.rau\nx const 1\n").unwrap();
+        fs::write(dir.join("b.rau"), "include a.rau\ny const 2\n").unwrap();
+
+        let line = format!("include {}", dir.join("a.rau").display());
+        let files = match parse_command(&line).unwrap() {
+            Some(Command::Config(_, files)) => files,
+            cmd => panic!("unexpected {:?}", cmd),
+        };
+        let names: Vec<&str> = files.iter().map(|(f, _, _)| f.as_str()).collect();
+        assert_eq!(names, vec![dir.join("a.rau").to_str().unwrap(), dir.join("b.rau").to_str().unwrap()]);
+        assert_eq!(files[1].2, "include a.rau\ny const 2\n");
+    }
+
+    #[test]
+    fn included_files_are_not_read_again() {
+        let dir = env::temp_dir().join("rau_console_test");
+        fs::create_dir_all(&dir).unwrap();
+        let fname = dir.join("c.rau");
+        fs::write(&fname, "z const 3\n").unwrap();
+        let cmd = parse_command(&format!("include {}", fname.display())).unwrap().unwrap();
+        fs::remove_file(&fname).unwrap();
+
+        let mut l = Loader::new();
+        let mut rack = Rack::new();
+        run(&mut l, &mut rack, cmd).unwrap();
+        assert_eq!(rack.get_output("z", "out").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse_command("  \n").unwrap(), None);
+        assert_eq!(parse_command("rm lfo:out filt:freq").unwrap(),
+            Some(Command::RemoveWire("lfo".into(), "out".into(), "filt".into(), "freq".into())));
+        assert_eq!(parse_command("get env:out\n").unwrap(), Some(Command::Get("env".into(), "out".into())));
+        assert!(parse_command("get env").is_err());
+        assert_eq!(parse_command("set filt:freq 800").unwrap(), Some(Command::Config("set filt:freq 800".into(), vec![])));
+    }
+}
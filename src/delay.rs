@@ -1,11 +1,14 @@
 
 use crate::loader::Loader;
-use crate::units::{FracSamples, Sec};
+use crate::units::{FracSamples, Sec, SAMPLE_RATE};
 use crate::module::*;
 
 const MINDEPTH: f64 = 1e-3; // at 48khz this is 48 samples
 
 pub struct Delay {
+    maxdelay: Sec,
+    delay: Sec, // current delay, initially maxdelay
+    rate: f64,
     ring: Vec<f64>,
     dry: f64,
     fb: f64,
@@ -18,6 +21,16 @@ pub struct Delay {
 }
 
 impl Delay {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("delay", "effect", "delay line with feedback")
+            .arg(ArgDescr::new("depth", ArgType::Float(Unit::Sec)).range(0.0, f64::INFINITY).descr("longest delay"))
+            .arg(ArgDescr::new("dry", ArgType::Float(Unit::Gain)).descr("level of the undelayed input in the output"))
+            .arg(ArgDescr::new("feedback", ArgType::Float(Unit::Gain)).descr("level of the delayed signal fed back in"))
+            .input(TerminalDescr::new("in", Unit::Audio).required())
+            .input(TerminalDescr::new("delay", Unit::Sec).descr("delay time, up to depth"))
+            .output(TerminalDescr::new("out", Unit::Audio))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 4 {
             return Err(format!("usage: {} depth dry feedback", args[0]));
@@ -34,8 +47,11 @@ impl Delay {
     }
 
     pub fn new(maxdelay: impl Into<FracSamples>, dry: f64, fb: f64) -> Self {
-        let FracSamples(maxd,_) = maxdelay.into();
+        let FracSamples(maxd, frac) = maxdelay.into();
         Self{
+            maxdelay: Sec((maxd as f64 + frac) / SAMPLE_RATE),
+            delay: Sec((maxd as f64 + frac) / SAMPLE_RATE),
+            rate: SAMPLE_RATE,
             ring: vec![0.0; maxd + 2],
             dry,
             fb,
@@ -56,14 +72,29 @@ impl Delay {
     pub fn set_dry(&mut self, v: f64) {
         self.dry = v;
     }
-    pub fn set_delay(&mut self, v: impl Into<FracSamples>) {
-        let FracSamples(delay, frac_delay) = v.into();
+    pub fn fb(&self) -> f64 {
+        self.fb
+    }
+    // Resize the delay line for a new sampling rate, dropping its contents.
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        let FracSamples(maxd, _) = self.maxdelay.to_frac_samples(rate);
+        self.rate = rate;
+        self.ring = vec![0.0; maxd + 2];
+        self.rpos = 1;
+        self.wpos = 0;
+        self.interp = 0.0;
+        self.delay = self.maxdelay;
+    }
+
+    pub fn set_delay(&mut self, v: Sec) {
+        let FracSamples(delay, frac_delay) = v.to_frac_samples(self.rate);
         assert!(delay + 1 <= self.ring.len());
         assert!(0.0 <= frac_delay && frac_delay < 1.0);
         
         // rpos lags wpos by delay samples.
         self.rpos = mod_sub(self.wpos, delay, self.ring.len());
         self.interp = frac_delay;
+        self.delay = v;
     }
 
     pub fn advance(&mut self) -> f64 {
@@ -90,8 +121,14 @@ impl Delay {
 
 impl Module for Delay {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in".to_string(), "delay".to_string()],
-         vec!["out".to_string()])
+        let (mut ins, outs) = Delay::descr().terminals();
+        ins[1].max = self.maxdelay.0;
+        ins[1].default = self.maxdelay.0;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["delay".to_string(), self.maxdelay.0.to_string(), self.dry.to_string(), self.fb.to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -99,13 +136,26 @@ impl Module for Delay {
         None
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.inp); }
+        if idx == 1 { return Some(self.delay.0); }
+        None
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 { self.set_input(value); }
 
-        // XXX show error if value is too large?  right now we silently wrap around
         if idx == 1 { self.set_delay(Sec(value)); }
     }
 
+    fn set_sample_rate(&mut self, rate: f64) {
+        Delay::set_sample_rate(self, rate);
+    }
+
+    fn has_delay(&self) -> bool {
+        true
+    }
+
     fn advance(&mut self) -> bool {
         Delay::advance(self);
         true
@@ -113,5 +163,5 @@ impl Module for Delay {
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("delay", Delay::from_cmd);
+    l.register(Delay::descr(), Delay::from_cmd);
 }
@@ -0,0 +1,277 @@
+
+use crate::module::{TerminalDescr, Unit};
+use crate::units::Quantity;
+
+// Type of an argument used to create a module
+#[derive(Clone, Debug)]
+pub enum ArgType {
+    Float(Unit),
+    Int,
+    Choice(Vec<String>),
+    Str,
+}
+
+impl ArgType {
+    pub fn choice(choices: &[&str]) -> Self {
+        ArgType::Choice(choices.iter().map(|s| s.to_string()).collect())
+    }
+
+    fn name(&self) -> String {
+        match self {
+            ArgType::Float(unit) => format!("float ({})", unit.name()),
+            ArgType::Int => "int".to_string(),
+            ArgType::Choice(choices) => format!("one of {}", choices.join(", ")),
+            ArgType::Str => "string".to_string(),
+        }
+    }
+}
+
+// Description of an argument used to create a module
+#[derive(Clone, Debug)]
+pub struct ArgDescr {
+    pub name: String,
+    pub typ: ArgType,
+    pub min: f64,
+    pub max: f64,
+    pub default: Option<String>, // arguments with defaults are optional
+    pub descr: String,
+}
+
+impl ArgDescr {
+    pub fn new(name: &str, typ: ArgType) -> Self {
+        ArgDescr {
+            name: name.to_owned(),
+            typ,
+            min: f64::NEG_INFINITY,
+            max: f64::INFINITY,
+            default: None,
+            descr: String::new(),
+        }
+    }
+
+    pub fn range(mut self, min: f64, max: f64) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    pub fn default(mut self, v: &str) -> Self {
+        self.default = Some(v.to_owned());
+        self
+    }
+
+    pub fn descr(mut self, descr: &str) -> Self {
+        self.descr = descr.to_owned();
+        self
+    }
+
+    fn check_range(&self, val: &str, v: f64) -> Result<(), String> {
+        if v < self.min || v > self.max {
+            Err(format!("{} must be between {} and {}, not {}", self.name, self.min, self.max, val))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Check the value of an argument and convert any unit in it to what the argument expects.
+    pub fn value(&self, val: &str) -> Result<String, String> {
+        match self.typ {
+            ArgType::Float(unit) => {
+                let v = parse_value(&self.name, val, unit)?;
+                self.check_range(val, v)?;
+                Ok(v.to_string())
+            },
+            ArgType::Int => {
+                if let Ok(v) = val.parse::<f64>() {
+                    self.check_range(val, v)?;
+                } // parse errors are reported when the module is created
+                Ok(val.to_owned())
+            },
+            ArgType::Choice(ref choices) => {
+                if choices.iter().any(|c| c == val) {
+                    Ok(val.to_owned())
+                } else {
+                    Err(format!("{} must be one of {}, not '{}'", self.name, choices.join(", "), val))
+                }
+            },
+            ArgType::Str => Ok(val.to_owned()),
+        }
+    }
+}
+
+// Parse a number, which may carry a unit, into a value in the given unit.
+pub fn parse_value(name: &str, val: &str, unit: Unit) -> Result<f64, String> {
+    let q = val.parse::<Quantity>().map_err(|e| format!("{}: {}", name, e))?;
+    convert(q, unit).ok_or(format!("{} is in {}, not a {} like '{}'", name, unit.name(), q.kind(), val))
+}
+
+// Value of a quantity in a unit, if it can be expressed in that unit.
+// Times and frequencies convert to each other as periods.
+fn convert(q: Quantity, unit: Unit) -> Option<f64> {
+    let gain = |db: f64| 10.0_f64.powf(db / 20.0);
+    match (q, unit) {
+        (Quantity::Plain(v), _) => Some(v),
+        (Quantity::Freq(hz), Unit::Hz) => Some(hz.0),
+        (Quantity::Time(t), Unit::Hz) => Some(1.0 / t.0),
+        (Quantity::Time(t), Unit::Sec) => Some(t.0),
+        (Quantity::Freq(hz), Unit::Sec) => Some(1.0 / hz.0),
+        (Quantity::Db(db), Unit::Db) => Some(db),
+        (Quantity::Db(db), Unit::Gain) => Some(gain(db)),
+        (Quantity::Freq(hz), Unit::Audio | Unit::Value) => Some(hz.0),
+        (Quantity::Time(t), Unit::Audio | Unit::Value) => Some(t.0),
+        (Quantity::Db(db), Unit::Audio | Unit::Value) => Some(gain(db)),
+        _ => None,
+    }
+}
+
+// Description of a module type that can be created from a config file
+#[derive(Clone, Debug)]
+pub struct ModuleDescr {
+    pub name: String,
+    pub category: &'static str,
+    pub descr: String,
+    pub args: Vec<ArgDescr>,
+    pub inputs: Vec<TerminalDescr>,
+    pub outputs: Vec<TerminalDescr>,
+}
+
+impl ModuleDescr {
+    pub fn new(name: &str, category: &'static str, descr: &str) -> Self {
+        ModuleDescr {
+            name: name.to_owned(),
+            category,
+            descr: descr.to_owned(),
+            args: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, a: ArgDescr) -> Self {
+        self.args.push(a);
+        self
+    }
+
+    pub fn input(mut self, t: TerminalDescr) -> Self {
+        self.inputs.push(t);
+        self
+    }
+
+    pub fn output(mut self, t: TerminalDescr) -> Self {
+        self.outputs.push(t);
+        self
+    }
+
+    // Inputs and outputs, for a module's get_terminals to start from before
+    // adjusting ranges and defaults to its sampling rate and settings.
+    pub fn terminals(self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        (self.inputs, self.outputs)
+    }
+
+    // One line summary of the arguments, optional ones in brackets.
+    pub fn usage(&self) -> String {
+        let mut s = self.name.clone();
+        let mut optional = false;
+        for a in self.args.iter() {
+            if a.default.is_some() && !optional {
+                s.push_str(" [");
+                optional = true;
+            } else {
+                s.push(' ');
+            }
+            s.push_str(&a.name);
+        }
+        if optional {
+            s.push(']');
+        }
+        s
+    }
+
+    // Full help text.
+    pub fn help(&self) -> String {
+        let mut s = format!("{} ({})\n  {}\n", self.usage(), self.category, self.descr);
+        if !self.args.is_empty() {
+            s.push_str("arguments:\n");
+            for a in self.args.iter() {
+                s.push_str(&format!("  {:10} {}", a.name, a.typ.name()));
+                if a.min.is_finite() || a.max.is_finite() {
+                    s.push_str(&format!(", {} to {}", a.min, a.max));
+                }
+                if let Some(ref def) = a.default {
+                    s.push_str(&format!(", default {}", def));
+                }
+                s.push_str(&format!("\n             {}\n", a.descr));
+            }
+        }
+        for (title, terms) in [("inputs", &self.inputs), ("outputs", &self.outputs)].iter() {
+            if terms.is_empty() {
+                continue;
+            }
+            s.push_str(&format!("{}:\n", title));
+            for t in terms.iter() {
+                s.push_str(&format!("  {:10} {}, {} to {}", t.name, t.unit.name(), t.min, t.max));
+                if t.required {
+                    s.push_str(", required");
+                }
+                if !t.descr.is_empty() {
+                    s.push_str(&format!("\n             {}", t.descr));
+                }
+                s.push('\n');
+            }
+        }
+        s
+    }
+
+    pub fn to_json(&self) -> String {
+        let args: Vec<String> = self.args.iter().map(|a| {
+            let choices = match a.typ {
+                ArgType::Choice(ref cs) => format!(", \"choices\": [{}]", cs.iter().map(|c| json_str(c)).collect::<Vec<_>>().join(", ")),
+                _ => String::new(),
+            };
+            let typ = match a.typ {
+                ArgType::Float(unit) => format!("\"float\", \"unit\": {}", json_str(unit.name())),
+                ArgType::Int => "\"int\"".to_string(),
+                ArgType::Choice(_) => "\"choice\"".to_string(),
+                ArgType::Str => "\"string\"".to_string(),
+            };
+            let default = match a.default {
+                Some(ref d) => json_str(d),
+                None => "null".to_string(),
+            };
+            format!("{{\"name\": {}, \"type\": {}{}, \"min\": {}, \"max\": {}, \"default\": {}, \"descr\": {}}}",
+                json_str(&a.name), typ, choices, json_num(a.min), json_num(a.max), default, json_str(&a.descr))
+        }).collect();
+        format!("{{\"name\": {}, \"category\": {}, \"descr\": {}, \"usage\": {}, \"args\": [{}], \"inputs\": [{}], \"outputs\": [{}]}}",
+            json_str(&self.name), json_str(self.category), json_str(&self.descr), json_str(&self.usage()),
+            args.join(", "), terminals_json(&self.inputs), terminals_json(&self.outputs))
+    }
+}
+
+fn terminals_json(terms: &[TerminalDescr]) -> String {
+    let ts: Vec<String> = terms.iter().map(|t|
+        format!("{{\"name\": {}, \"unit\": {}, \"min\": {}, \"max\": {}, \"default\": {}, \"required\": {}, \"descr\": {}}}",
+            json_str(&t.name), json_str(t.unit.name()), json_num(t.min), json_num(t.max), json_num(t.default), t.required, json_str(&t.descr))
+        ).collect();
+    ts.join(", ")
+}
+
+// infinite ranges have no JSON representation
+fn json_num(x: f64) -> String {
+    if x.is_finite() { format!("{}", x) } else { "null".to_string() }
+}
+
+pub fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
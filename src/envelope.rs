@@ -1,12 +1,17 @@
 
 use std::convert::Into;
-use crate::units::{Samples, Sec};
+use crate::units::{Samples, Sec, SAMPLE_RATE};
 use crate::module::*;
 
 #[derive(Debug)]
 enum EnvMode{ Attack, Decay, Release }
 
 pub struct Envelope {
+    attack_time: Sec,
+    decay_time: Sec,
+    release_time: Sec,
+    rate: f64,
+
     attack: f64,
     decay: f64,
     sustain: f64,
@@ -16,10 +21,12 @@ pub struct Envelope {
     val: f64,
     gate: bool,
     last_gate: bool,
+    held: Vec<u8>, // notes held down by note events
+    trig: bool, // start an attack on the next sample
 }
 
 // exponential decay factor to decay to 10% of starting value after t seconds.
-fn decay_factor(time: impl Into<Samples>) -> f64 {
+fn decay_factor(time: Sec, rate: f64) -> f64 {
     // exp decay:
     // x[n+1] = r x[n],  x[0] = 1.0
     // x[n] = r^n
@@ -29,12 +36,23 @@ fn decay_factor(time: impl Into<Samples>) -> f64 {
     // -1 = N * log(r)
     // -1/N = log(r)
     // r = 10^(-1/N)
-    let Samples(n) =  time.into();
-    assert!(n > 0);
-    (10.0_f64).powf(-1.0 / (n as f64))
+    // times shorter than a sample, including 0, take one sample
+    let n = (time.0 * rate).max(1.0);
+    (10.0_f64).powf(-1.0 / n)
 }
 
 impl Envelope {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("envelope", "modulation", "attack decay sustain release envelope")
+            .arg(ArgDescr::new("attack", ArgType::Float(Unit::Sec)).range(0.0, f64::INFINITY).descr("attack time"))
+            .arg(ArgDescr::new("decay", ArgType::Float(Unit::Sec)).range(0.0, f64::INFINITY).descr("decay time"))
+            .arg(ArgDescr::new("sustain", ArgType::Float(Unit::Gain)).range(0.0, 1.0).descr("sustain level"))
+            .arg(ArgDescr::new("release", ArgType::Float(Unit::Sec)).range(0.0, f64::INFINITY).descr("release time"))
+            .input(TerminalDescr::new("gate", Unit::Gate).required().descr("start attack when high, release when low"))
+            .input(TerminalDescr::new("notes", Unit::Event).descr("start attack on each note or trigger, release when no notes are held"))
+            .output(TerminalDescr::new("out", Unit::Gain).range(0.0, 1.0))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 5 {
             return Err(format!("usage: {} attack decay sustain release", args[0]));
@@ -50,16 +68,31 @@ impl Envelope {
     // s as a level from 0..=1.0
     pub fn new(a: impl Into<Samples>, d: impl Into<Samples>, s: f64, r: impl Into<Samples>) -> Self {
         assert!(0.0 <= s && s <= 1.0);
-        Envelope {
-            attack: decay_factor(a),
-            decay: decay_factor(d),
+        let (a, d, r): (Samples, Samples, Samples) = (a.into(), d.into(), r.into());
+        let mut env = Envelope {
+            attack_time: a.into(),
+            decay_time: d.into(),
+            release_time: r.into(),
+            rate: SAMPLE_RATE,
+            attack: 0.0,
+            decay: 0.0,
             sustain: s,
-            release: decay_factor(r),
+            release: 0.0,
             mode: EnvMode::Release,
             val: 0.0,
             gate: false,
             last_gate: false,
-        }
+            held: Vec::new(),
+            trig: false,
+        };
+        env.recalc();
+        env
+    }
+
+    fn recalc(&mut self) {
+        self.attack = decay_factor(self.attack_time, self.rate);
+        self.decay = decay_factor(self.decay_time, self.rate);
+        self.release = decay_factor(self.release_time, self.rate);
     }
 
     pub fn gen(&self) -> f64 { self.val }
@@ -71,14 +104,20 @@ impl Envelope {
 
 impl Module for Envelope {
     fn advance(&mut self) -> bool {
+        // the gate is open while the gate input is high or a note is held
+        let gate = self.gate || !self.held.is_empty();
         let last_gate = self.last_gate;
-        self.last_gate = self.gate;
-        if self.gate != last_gate {
-            match self.gate {
+        self.last_gate = gate;
+        if gate != last_gate {
+            match gate {
                 true => self.mode = EnvMode::Attack,
                 false => self.mode = EnvMode::Release,
             };
         }
+        if self.trig {
+            self.trig = false;
+            self.mode = EnvMode::Attack;
+        }
 
         match self.mode {
         EnvMode::Attack =>
@@ -86,7 +125,8 @@ impl Module for Envelope {
                 self.val = 1.1 - (1.1 - self.val) * self.attack;
             } else {
                 self.val = 1.0;
-                self.mode = EnvMode::Decay;
+                // a trigger without a held gate goes straight to release
+                self.mode = if gate { EnvMode::Decay } else { EnvMode::Release };
             },
         EnvMode::Decay =>
             if self.val > self.sustain {
@@ -98,20 +138,86 @@ impl Module for Envelope {
         true
     }
 
+    fn process_block(&mut self, len: usize, inputs: &[&[f64]], outputs: &mut [&mut [f64]],
+                     events: &[Timed], _out_events: &mut Vec<Timed>) -> bool {
+        let mut next = 0;
+        for (n, out) in outputs[0][.. len].iter_mut().enumerate() {
+            if let Some(&v) = inputs[0].get(n) { self.set_gate(v >= 0.5); }
+            while next < events.len() && events[next].time <= n {
+                self.send_event(events[next].term, events[next].event);
+                next += 1;
+            }
+            Module::advance(self);
+            *out = self.val;
+        }
+        true
+    }
+
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["gate".to_string()],
-         vec!["out".to_string()])
+        Envelope::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["envelope".to_string(), self.attack_time.0.to_string(), self.decay_time.0.to_string(),
+             self.sustain.to_string(), self.release_time.0.to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
         if idx == 0 { Some(self.val) } else { None }
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { Some(if self.gate { 1.0 } else { 0.0 }) } else { None }
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 { self.set_gate(value >= 0.5) }
     }
+
+    fn send_event(&mut self, idx: usize, event: Event) {
+        if idx != 1 {
+            return;
+        }
+        match event {
+            Event::NoteOn(n, _) => {
+                self.held.retain(|&h| h != n);
+                self.held.push(n);
+                self.trig = true;
+            },
+            Event::NoteOff(n) => self.held.retain(|&h| h != n),
+            Event::Trigger => self.trig = true,
+            _ => (),
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        self.recalc();
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("envelope", Envelope::from_cmd);
+    l.register(Envelope::descr(), Envelope::from_cmd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_times_take_a_sample() {
+        let mut env = Envelope::new(Sec(0.0), Sec(0.0), 0.5, Sec(1e-9));
+        env.set_gate(true);
+        Module::advance(&mut env);
+        assert!(env.gen() > 0.9);
+        for _ in 0 .. 10 {
+            Module::advance(&mut env);
+        }
+        assert!((env.gen() - 0.5).abs() < 0.01);
+        env.set_gate(false);
+        for _ in 0 .. 10 {
+            Module::advance(&mut env);
+        }
+        assert!(env.gen() < 0.01);
+    }
 }
@@ -11,11 +11,18 @@ fn conv(x: f64) -> (u8, u8) {
 }
 
 pub struct Tape {
+    fname: String,
     f: BufWriter<File>,
     val: f64,
 }
 
 impl Tape {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("file", "output", "write raw big-endian 16-bit mono samples to a file")
+            .arg(ArgDescr::new("fname", ArgType::Str).descr("file to write"))
+            .input(TerminalDescr::new("in", Unit::Audio).required())
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 2 {
             return Err(format!("usage: {} fname", args[0]));
@@ -28,6 +35,7 @@ impl Tape {
         let f = File::create(fname).expect("cant open"); // XXX more graceful error
         let buff = BufWriter::new(f);
         Tape {
+            fname: fname.to_owned(),
             f: buff,
             val: 0.0,
         }
@@ -48,14 +56,21 @@ impl Tape {
 
 impl Module for Tape {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in".to_string()],
-         vec![])
+        Tape::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["file".to_string(), self.fname.clone()]
     }
 
     fn get_output(&self, _idx: usize) -> Option<f64> {
         unreachable!();
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { Some(self.val) } else { None }
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 {
             self.val = value;
@@ -70,6 +85,6 @@ impl Module for Tape {
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("file", Tape::from_cmd);
+    l.register(Tape::descr(), Tape::from_cmd);
 }
 
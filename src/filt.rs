@@ -1,12 +1,29 @@
 
 use std::str::FromStr;
 use std::convert::Into;
-use crate::units::{RadPS, Hz};
+use crate::units::{RadPS, Hz, SAMPLE_RATE, MAXHZ};
 use crate::module::*;
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum FiltType { LP, BP, Notch, HP, LowShelf, CenterShelf, HighShelf }
 
+// names of the filter types, as parsed from config files
+pub const FILTTYPES: &[&str] = &["lp", "bp", "notch", "hp", "lowshelf", "centershelf", "highshelf"];
+
+impl FiltType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FiltType::LP => "lp",
+            FiltType::BP => "bp",
+            FiltType::Notch => "notch",
+            FiltType::HP => "hp",
+            FiltType::LowShelf => "lowshelf",
+            FiltType::CenterShelf => "centershelf",
+            FiltType::HighShelf => "highshelf",
+        }
+    }
+}
+
 impl Default for FiltType {
     fn default() -> Self { FiltType::LP }
 }
@@ -28,7 +45,8 @@ impl FromStr for FiltType {
 #[derive(Default, Debug)]
 pub struct Filter {
     typ: FiltType,
-    freq: f64,
+    freq: Hz,
+    rate: f64,
     gain: f64,
     q: f64,
 
@@ -44,6 +62,17 @@ pub struct Filter {
 }
 
 impl Filter {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("filter", "filter", "biquad filter")
+            .arg(ArgDescr::new("type", ArgType::choice(FILTTYPES)).descr("filter response"))
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("cutoff or center frequency"))
+            .arg(ArgDescr::new("gain", ArgType::Float(Unit::Db)).default("0.0").descr("gain, for shelf filters"))
+            .arg(ArgDescr::new("q", ArgType::Float(Unit::Value)).range(0.0, f64::INFINITY).default("0.707").descr("quality factor"))
+            .input(TerminalDescr::new("in", Unit::Audio).required())
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("cutoff or center frequency"))
+            .output(TerminalDescr::new("out", Unit::Audio))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 5 {
             return Err(format!("usage: {} filttype freq gain q", args[0]));
@@ -56,10 +85,10 @@ impl Filter {
     }
 
     pub fn new(typ: FiltType, freq: impl Into<RadPS>, gain: f64, q: f64) -> Self {
-        let RadPS(w) = freq.into();
         let mut v = Self { 
             typ,
-            freq: w,
+            freq: Hz::from(freq.into()),
+            rate: SAMPLE_RATE,
             gain,
             q,
             ..Default::default() 
@@ -68,9 +97,14 @@ impl Filter {
         v 
     }
 
+    // freq is relative to the default sampling rate, like the constructor's.
     pub fn set_freq(&mut self, freq: impl Into<RadPS>) {
-        let RadPS(w) = freq.into();
-        self.freq = w;
+        self.freq = Hz::from(freq.into());
+        self.recalc();
+    }
+
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
         self.recalc();
     }
 
@@ -78,7 +112,7 @@ impl Filter {
         // reference: https://webaudio.github.io/Audio-EQ-Cookbook/audio-eq-cookbook.html
         #[allow(non_snake_case)]
         let A = 10.0_f64.powf(self.gain/40.0);
-        let w = self.freq;
+        let RadPS(w) = self.freq.to_radps(self.rate);
         let q = self.q;
 
         let cw = w.cos();
@@ -158,18 +192,37 @@ impl Filter {
 impl Module for Filter {
     // XXX terminals for freq, gain and Q and type?
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in".to_string(),
-              "freq".to_string()],
-         vec!["out".to_string()])
+        let (mut ins, outs) = Filter::descr().terminals();
+        ins[1].max = self.rate / 2.0;
+        ins[1].default = self.freq.0;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["filter".to_string(), self.typ.name().to_string(), self.freq.0.to_string(),
+             self.gain.to_string(), self.q.to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
         if idx == 0 { Some(self.val) } else { None }
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.inp); }
+        if idx == 1 { return Some(self.freq.0); }
+        None
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 { self.inp = value; }
-        if idx == 1 { self.set_freq(Hz(value)); }
+        if idx == 1 {
+            self.freq = Hz(value);
+            self.recalc();
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        Filter::set_sample_rate(self, rate);
     }
 
     fn advance(&mut self) -> bool {
@@ -180,9 +233,25 @@ impl Module for Filter {
         //println!("{:?}", self);
         true
     }
+
+    fn process_block(&mut self, len: usize, inputs: &[&[f64]], outputs: &mut [&mut [f64]],
+                     _events: &[Timed], _out_events: &mut Vec<Timed>) -> bool {
+        for (n, out) in outputs[0][.. len].iter_mut().enumerate() {
+            if let Some(&v) = inputs[0].get(n) { self.inp = v; }
+            // the coefficients only change with the frequency
+            if let Some(&v) = inputs[1].get(n) {
+                if v != self.freq.0 {
+                    self.set_input(1, v);
+                }
+            }
+            Module::advance(self);
+            *out = self.val;
+        }
+        true
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("filter", Filter::from_cmd);
+    l.register(Filter::descr(), Filter::from_cmd);
 }
 
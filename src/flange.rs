@@ -1,8 +1,8 @@
 
-use crate::additive::Function;
+use crate::additive::{Function, FUNCTIONS};
 use crate::simple::Gen as Osc;
 use crate::delay::Delay;
-use crate::units::{RadPS, Hz, Sec};
+use crate::units::{RadPS, Hz, Sec, MAXHZ};
 use crate::module::*;
 
 pub struct Flange {
@@ -16,6 +16,18 @@ pub struct Flange {
 const MAXDELAY: f64 = 1e-3; // in seconds, about 25 comb notches
 
 impl Flange {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("flange", "effect", "flanger sweeping a short delay with an lfo")
+            .arg(ArgDescr::new("functype", ArgType::choice(FUNCTIONS)).descr("lfo wave shape"))
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("lfo frequency"))
+            .arg(ArgDescr::new("manual", ArgType::Float(Unit::Value)).range(0.0, 1.0).descr("center of the sweep, as a fraction of the longest delay"))
+            .arg(ArgDescr::new("width", ArgType::Float(Unit::Value)).range(-1.0, 1.0).descr("width of the sweep, negative inverts the lfo"))
+            .arg(ArgDescr::new("feedback", ArgType::Float(Unit::Gain)).descr("level of the delayed signal fed back in"))
+            .input(TerminalDescr::new("in", Unit::Audio).required())
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("lfo frequency"))
+            .output(TerminalDescr::new("out", Unit::Audio))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 6 {
             return Err(format!("usage: {} functype freq manual width feedback", args[0]));
@@ -61,6 +73,11 @@ impl Flange {
         self.width = width;
     }
 
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        self.delay.set_sample_rate(rate);
+        self.lfo.set_sample_rate(rate);
+    }
+
     pub fn set_fb(&mut self, fb: f64) {
         self.delay.set_fb(fb);
     }
@@ -88,9 +105,15 @@ impl Flange {
 impl Module for Flange {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
         // XXX more inputs
-        (vec!["in".to_string(),
-              "freq".to_string()],
-         vec!["out".to_string()])
+        let (mut ins, outs) = Flange::descr().terminals();
+        ins[1].max = self.lfo.rate() / 2.0;
+        ins[1].default = self.lfo.freq().0;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["flange".to_string(), self.lfo.func().name().to_string(), self.lfo.freq().0.to_string(),
+             self.manual.to_string(), self.width.to_string(), self.delay.fb().to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -98,6 +121,11 @@ impl Module for Flange {
         None
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 1 { return Some(self.lfo.freq().0); }
+        None
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 { self.set_input(value); }
         if idx == 1 { self.set_freq(Hz(value)); }
@@ -107,9 +135,13 @@ impl Module for Flange {
         Flange::advance(self);
         true
     }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        Flange::set_sample_rate(self, rate);
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("flange", Flange::from_cmd);
+    l.register(Flange::descr(), Flange::from_cmd);
 }
 
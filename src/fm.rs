@@ -0,0 +1,428 @@
+
+/*
+ * FM synthesis: operators are sine oscillators whose phase is pushed around
+ * by the outputs of other operators. An fmop is a single operator to wire up
+ * by hand, and an fmvoice wires up 4 or 6 operators inside of it, following
+ * one of the algorithms of Yamaha's DX synthesizers.
+ */
+
+use std::f64::consts::PI;
+use crate::descr::parse_value;
+use crate::envelope::Envelope;
+use crate::units::{Hz, Sec, SAMPLE_RATE, MAXHZ};
+use crate::module::*;
+
+const MAXRATIO: f64 = 64.0;
+const MAXPM: f64 = 64.0; // radians
+const MAXFEEDBACK: f64 = 4.0; // radians
+
+// A sine oscillator with a phase offset. Feedback uses the average of its
+// last two outputs, as the DX synthesizers do, so that strong feedback turns
+// into noise instead of oscillating at nyquist.
+#[derive(Clone, Debug, Default)]
+struct Op {
+    phase: f64, // fraction of a cycle, invariant: 0 <= phase < 1
+    out: f64,
+    last: f64,
+}
+
+impl Op {
+    fn feedback(&self) -> f64 {
+        0.5 * (self.out + self.last)
+    }
+
+    // Move freq cycles along, which can be negative, and return the sine
+    // of the phase plus pm radians.
+    fn advance(&mut self, freq: f64, pm: f64) -> f64 {
+        self.phase = (self.phase + freq.clamp(-0.5, 0.5)).rem_euclid(1.0);
+        self.last = self.out;
+        self.out = (2.0 * PI * self.phase + pm).sin();
+        self.out
+    }
+}
+
+pub struct FmOp {
+    freq: Hz,
+    ratio: f64,
+    pm: f64,
+    fm: f64,
+    feedback: f64,
+    rate: f64,
+    op: Op,
+}
+
+impl FmOp {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("fmop", "source", "sine operator for phase and frequency modulation")
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("initial frequency"))
+            .arg(ArgDescr::new("ratio", ArgType::Float(Unit::Value)).range(0.0, MAXRATIO).default("1.0").descr("multiple of freq to run at"))
+            .arg(ArgDescr::new("feedback", ArgType::Float(Unit::Value)).range(0.0, MAXFEEDBACK).default("0.0").descr("initial feedback"))
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("base frequency"))
+            .input(TerminalDescr::new("ratio", Unit::Value).range(0.0, MAXRATIO).default(1.0).descr("multiple of freq to run at"))
+            .input(TerminalDescr::new("pm", Unit::Value).range(-MAXPM, MAXPM).default(0.0).descr("phase modulation in radians"))
+            .input(TerminalDescr::new("fm", Unit::Hz).range(-MAXHZ, MAXHZ).default(0.0).descr("frequency modulation added to freq * ratio, which can go through zero"))
+            .input(TerminalDescr::new("feedback", Unit::Value).range(0.0, MAXFEEDBACK).default(0.0).descr("phase modulation by its own output, in radians"))
+            .output(TerminalDescr::new("out", Unit::Audio).range(-1.0, 1.0))
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() < 2 || args.len() > 4 {
+            return Err(format!("usage: {} freq [ratio feedback]", args[0]));
+        }
+        let freq = parse::<f64>("freq", args[1])?;
+        let ratio = if args.len() >= 3 { parse::<f64>("ratio", args[2])? } else { 1.0 };
+        let feedback = if args.len() >= 4 { parse::<f64>("feedback", args[3])? } else { 0.0 };
+        Ok( modref_new(Self::new(Hz(freq), ratio, feedback)) )
+    }
+
+    pub fn new(freq: Hz, ratio: f64, feedback: f64) -> Self {
+        FmOp {
+            freq,
+            ratio,
+            pm: 0.0,
+            fm: 0.0,
+            feedback,
+            rate: SAMPLE_RATE,
+            op: Op::default(),
+        }
+    }
+
+    pub fn advance(&mut self) -> f64 {
+        let freq = (self.freq.0 * self.ratio + self.fm) / self.rate;
+        let pm = self.pm + self.feedback * self.op.feedback();
+        self.op.advance(freq, pm)
+    }
+}
+
+impl Module for FmOp {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        let (mut ins, outs) = FmOp::descr().terminals();
+        ins[0].max = self.rate / 2.0;
+        ins[0].default = self.freq.0;
+        ins[1].default = self.ratio;
+        ins[4].default = self.feedback;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["fmop".to_string(), self.freq.0.to_string(), self.ratio.to_string(), self.feedback.to_string()]
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.op.out); }
+        None
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        match idx {
+            0 => Some(self.freq.0),
+            1 => Some(self.ratio),
+            2 => Some(self.pm),
+            3 => Some(self.fm),
+            4 => Some(self.feedback),
+            _ => None,
+        }
+    }
+
+    fn set_input(&mut self, idx: usize, value: f64) {
+        match idx {
+            0 => self.freq = Hz(value),
+            1 => self.ratio = value,
+            2 => self.pm = value,
+            3 => self.fm = value,
+            4 => self.feedback = value,
+            _ => (),
+        }
+    }
+
+    fn advance(&mut self) -> bool {
+        FmOp::advance(self);
+        true
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
+    }
+}
+
+// The algorithms, as pairs of operator numbers where the first modulates
+// the second, and the pair whose first feeds back into the second. Operators
+// that modulate no others are the carriers that get heard. Operators only
+// modulate lower numbered ones, so they run from the highest number down.
+// reference: the algorithm charts in the DX7 and DX21 manuals
+const ALGORITHMS6: [(&str, &str); 32] = [
+    ("65 54 43 21", "66"),
+    ("65 54 43 21", "22"),
+    ("65 54 32 21", "66"),
+    ("65 54 32 21", "46"),
+    ("65 43 21", "66"),
+    ("65 43 21", "56"),
+    ("65 53 43 21", "66"),
+    ("65 53 43 21", "44"),
+    ("65 53 43 21", "22"),
+    ("64 54 32 21", "33"),
+    ("64 54 32 21", "66"),
+    ("63 53 43 21", "22"),
+    ("63 53 43 21", "66"),
+    ("64 54 43 21", "66"),
+    ("64 54 43 21", "22"),
+    ("65 43 51 31 21", "66"),
+    ("65 43 51 31 21", "22"),
+    ("65 54 41 31 21", "33"),
+    ("65 64 32 21", "66"),
+    ("64 54 32 31", "33"),
+    ("65 64 32 31", "33"),
+    ("65 64 63 21", "66"),
+    ("65 64 32", "66"),
+    ("65 64 63", "66"),
+    ("65 64", "66"),
+    ("64 54 32", "66"),
+    ("64 54 32", "33"),
+    ("54 43 21", "55"),
+    ("65 43", "66"),
+    ("54 43", "55"),
+    ("65", "66"),
+    ("", "66"),
+];
+
+const ALGORITHMS4: [(&str, &str); 8] = [
+    ("43 32 21", "44"),
+    ("42 32 21", "44"),
+    ("41 32 21", "44"),
+    ("43 31 21", "44"),
+    ("43 21", "44"),
+    ("43 42 41", "44"),
+    ("43", "44"),
+    ("", "44"),
+];
+
+pub const OPS: &[&str] = &["4", "6"];
+
+// Operator indices of an algorithm's modulation pairs, counting from 0.
+fn pairs(s: &str) -> Vec<(usize, usize)> {
+    s.split_whitespace()
+        .map(|p| {
+            let b = p.as_bytes();
+            ((b[0] - b'1') as usize, (b[1] - b'1') as usize)
+        })
+        .collect()
+}
+
+// Values of a per-operator argument: one for all of the operators, or one for each.
+fn parse_list(name: &str, s: &str, unit: Unit, ops: usize) -> Result<Vec<f64>, String> {
+    let vals = s.split(',').map(|v| parse_value(name, v, unit)).collect::<Result<Vec<f64>, String>>()?;
+    match vals.len() {
+        1 => Ok(vec![vals[0]; ops]),
+        n if n == ops => Ok(vals),
+        n => Err(format!("{} has {} values, for {} operators", name, n, ops)),
+    }
+}
+
+fn show_list(vals: &[f64]) -> String {
+    if vals.iter().all(|&v| v == vals[0]) {
+        vals[0].to_string()
+    } else {
+        vals.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+    }
+}
+
+pub struct FmVoice {
+    algorithm: usize,
+    ratios: Vec<f64>,
+    levels: Vec<f64>, // output gain of carriers, modulation index in radians of modulators
+    feedback: f64,
+    times: [Vec<f64>; 3], // attack, decay and release of each operator
+    sustains: Vec<f64>,
+
+    mods: Vec<(usize, usize)>,
+    fb: (usize, usize),
+    carrier: Vec<bool>,
+    ops: Vec<Op>,
+    envs: Vec<Envelope>,
+
+    freq: Hz,
+    gate: bool,
+    vel: f64,
+    index: f64,
+    rate: f64,
+    val: f64,
+}
+
+impl FmVoice {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("fmvoice", "source", "voice of 4 or 6 fm operators, each with an envelope, in a DX algorithm")
+            .arg(ArgDescr::new("algorithm", ArgType::Int).range(1.0, 32.0).descr("DX7 algorithm 1 to 32, or DX21 algorithm 1 to 8 for 4 operators"))
+            .arg(ArgDescr::new("ops", ArgType::choice(OPS)).default("6").descr("number of operators"))
+            .arg(ArgDescr::new("ratios", ArgType::Str).default("1").descr("multiple of freq for each operator, one value or a comma separated value per operator"))
+            .arg(ArgDescr::new("levels", ArgType::Str).default("1").descr("gain of carriers, modulation index of modulators, per operator"))
+            .arg(ArgDescr::new("feedback", ArgType::Float(Unit::Value)).range(0.0, MAXFEEDBACK).default("0.0").descr("feedback of the algorithm's feedback operator"))
+            .arg(ArgDescr::new("attack", ArgType::Str).default("10ms").descr("attack time per operator"))
+            .arg(ArgDescr::new("decay", ArgType::Str).default("500ms").descr("decay time per operator"))
+            .arg(ArgDescr::new("sustain", ArgType::Str).default("0.5").descr("sustain level per operator"))
+            .arg(ArgDescr::new("release", ArgType::Str).default("300ms").descr("release time per operator"))
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("note frequency"))
+            .input(TerminalDescr::new("gate", Unit::Gate).required().descr("starts the envelopes when high, releases them when low"))
+            .input(TerminalDescr::new("vel", Unit::Gain).range(0.0, 1.0).default(1.0).descr("output level"))
+            .input(TerminalDescr::new("index", Unit::Value).range(0.0, 4.0).default(1.0).descr("scales the levels of the modulators"))
+            .input(TerminalDescr::new("feedback", Unit::Value).range(0.0, MAXFEEDBACK).default(0.0).descr("feedback of the algorithm's feedback operator"))
+            .output(TerminalDescr::new("out", Unit::Audio).range(-1.0, 1.0))
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 10 {
+            return Err(format!("usage: {} algorithm ops ratios levels feedback attack decay sustain release", args[0]));
+        }
+        let algorithm = parse::<usize>("algorithm", args[1])?;
+        let ops = parse::<usize>("ops", args[2])?;
+        let ratios = parse_list("ratios", args[3], Unit::Value, ops)?;
+        let levels = parse_list("levels", args[4], Unit::Gain, ops)?;
+        let feedback = parse::<f64>("feedback", args[5])?;
+        let attack = parse_list("attack", args[6], Unit::Sec, ops)?;
+        let decay = parse_list("decay", args[7], Unit::Sec, ops)?;
+        let sustain = parse_list("sustain", args[8], Unit::Gain, ops)?;
+        let release = parse_list("release", args[9], Unit::Sec, ops)?;
+        Ok( modref_new(Self::new(algorithm, ratios, levels, feedback, [attack, decay, release], sustain)?) )
+    }
+
+    // One ratio, level, attack, decay, sustain and release per operator.
+    pub fn new(algorithm: usize, ratios: Vec<f64>, levels: Vec<f64>, feedback: f64,
+               times: [Vec<f64>; 3], sustains: Vec<f64>) -> Result<Self, String> {
+        let ops = ratios.len();
+        let algs: &[(&str, &str)] = match ops {
+            4 => &ALGORITHMS4,
+            6 => &ALGORITHMS6,
+            _ => return Err(format!("can't have {} operators, only 4 or 6", ops)),
+        };
+        if !(1 ..= algs.len()).contains(&algorithm) {
+            return Err(format!("algorithm must be between 1 and {} for {} operators", algs.len(), ops));
+        }
+        if [&levels, &times[0], &times[1], &times[2], &sustains].iter().any(|v| v.len() != ops) {
+            return Err(format!("need a value for each of the {} operators", ops));
+        }
+        if times.iter().flatten().any(|&t| t < 0.0) {
+            return Err(format!("envelope times can't be negative"));
+        }
+        if sustains.iter().any(|&s| !(0.0 ..= 1.0).contains(&s)) {
+            return Err(format!("sustain levels must be between 0 and 1"));
+        }
+
+        let (mods, fb) = algs[algorithm - 1];
+        let mods = pairs(mods);
+        let carrier = (0 .. ops).map(|i| !mods.iter().any(|&(from, _)| from == i)).collect();
+        let envs = (0 .. ops)
+            .map(|i| Envelope::new(Sec(times[0][i]), Sec(times[1][i]), sustains[i], Sec(times[2][i])))
+            .collect();
+        Ok(FmVoice {
+            algorithm,
+            ratios,
+            levels,
+            feedback,
+            times,
+            sustains,
+            mods,
+            fb: pairs(fb)[0],
+            carrier,
+            ops: vec![Op::default(); ops],
+            envs,
+            freq: Hz(440.0),
+            gate: false,
+            vel: 1.0,
+            index: 1.0,
+            rate: SAMPLE_RATE,
+            val: 0.0,
+        })
+    }
+
+    pub fn advance(&mut self) -> f64 {
+        let n = self.ops.len();
+        let mut outs = [0.0; 6];
+        let mut sum = 0.0;
+        for i in (0 .. n).rev() {
+            let mut pm: f64 = self.mods.iter().filter(|&&(_, to)| to == i).map(|&(from, _)| outs[from]).sum();
+            if self.fb.1 == i {
+                pm += self.feedback * self.ops[self.fb.0].feedback();
+            }
+            let env = &mut self.envs[i];
+            env.set_gate(self.gate);
+            Module::advance(env);
+
+            let x = self.ops[i].advance(self.freq.0 * self.ratios[i] / self.rate, pm);
+            outs[i] = x * self.levels[i] * env.gen();
+            if self.carrier[i] {
+                sum += outs[i];
+            } else {
+                outs[i] *= self.index;
+            }
+        }
+        let carriers = self.carrier.iter().filter(|&&c| c).count();
+        self.val = self.vel * sum / carriers as f64;
+        self.val
+    }
+}
+
+impl Module for FmVoice {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        let (mut ins, outs) = FmVoice::descr().terminals();
+        ins[0].max = self.rate / 2.0;
+        ins[0].default = self.freq.0;
+        ins[4].default = self.feedback;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["fmvoice".to_string(), self.algorithm.to_string(), self.ops.len().to_string(),
+             show_list(&self.ratios), show_list(&self.levels), self.feedback.to_string(),
+             show_list(&self.times[0]), show_list(&self.times[1]), show_list(&self.sustains), show_list(&self.times[2])]
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.val); }
+        None
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        match idx {
+            0 => Some(self.freq.0),
+            1 => Some(if self.gate { 1.0 } else { 0.0 }),
+            2 => Some(self.vel),
+            3 => Some(self.index),
+            4 => Some(self.feedback),
+            _ => None,
+        }
+    }
+
+    fn set_input(&mut self, idx: usize, value: f64) {
+        match idx {
+            0 => self.freq = Hz(value),
+            1 => {
+                // start each note from the same phase, like the DX's key sync
+                let gate = value >= 0.5;
+                if gate && !self.gate {
+                    self.ops.iter_mut().for_each(|op| *op = Op::default());
+                }
+                self.gate = gate;
+            },
+            2 => self.vel = value,
+            3 => self.index = value,
+            4 => self.feedback = value,
+            _ => (),
+        }
+    }
+
+    fn advance(&mut self) -> bool {
+        FmVoice::advance(self);
+        true
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        for env in self.envs.iter_mut() {
+            env.set_sample_rate(rate);
+        }
+    }
+}
+
+pub fn init(l: &mut Loader) {
+    l.register(FmOp::descr(), FmOp::from_cmd);
+    l.register(FmVoice::descr(), FmVoice::from_cmd);
+}
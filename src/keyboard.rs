@@ -1,15 +1,18 @@
 
 use std::convert::Into;
 use device_query::{DeviceState, DeviceQuery, Keycode};
-use crate::units::{Samples, Hz, Cent, Sec};
+use crate::units::{Samples, Hz, Cent, MidiNote, Sec, SAMPLE_RATE};
 use crate::module::*;
 
 pub struct Keyboard {
     dev: DeviceState,
     keymap: Vec<(Keycode, f64)>,
-    poll_time: usize,
+    poll: Sec,
+    poll_time: usize, // poll in samples
 
     oldkeys: Vec<Keycode>,
+    held: Vec<(Keycode, u8)>, // keys playing notes, with their midi note
+    events: Vec<Event>, // note events sent on this sample
     timer: usize,
     val: f64,
     oct: f64,
@@ -66,6 +69,15 @@ fn new_keys(keys: &Vec<Keycode>, old: &Vec<Keycode>) -> Vec<Keycode> {
 }
 
 impl Keyboard {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("keyboard", "source", "play notes on the computer keyboard")
+            .arg(ArgDescr::new("polltime", ArgType::Float(Unit::Sec)).range(0.0, f64::INFINITY).descr("time between polls of the keyboard"))
+            .output(TerminalDescr::new("out", Unit::Hz).descr("frequency of the latest note"))
+            .output(TerminalDescr::new("gate", Unit::Gate).descr("high while a note is held"))
+            .output(TerminalDescr::new("quit", Unit::Gate).descr("high once quit was requested"))
+            .output(TerminalDescr::new("notes", Unit::Event).descr("note events for every key"))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 2 {
             return Err(format!("usage: {} polltime", args[0]));
@@ -80,11 +92,15 @@ impl Keyboard {
         println!();
         println!("Z - oct down, X - oct up");
         println!("Hit [Esc] to exit");
-        let Samples(time) = poll.into();
+        let poll: Samples = poll.into();
+        let Samples(time) = poll;
         Keyboard {
             dev: DeviceState::new(),
             keymap: make_keymap(),
             oldkeys: Vec::new(),
+            held: Vec::new(),
+            events: Vec::new(),
+            poll: poll.to_sec(SAMPLE_RATE),
             poll_time: time,
             timer: time,
             val: 0.0,
@@ -104,10 +120,11 @@ fn get_note(keymap: &Vec<(Keycode, f64)>, keys: &Vec<Keycode>) -> Option<f64> {
 
 impl Module for Keyboard {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec![],
-         vec!["out".to_string(),
-              "gate".to_string(),
-              "quit".to_string()])
+        Keyboard::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["keyboard".to_string(), self.poll.0.to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -121,7 +138,18 @@ impl Module for Keyboard {
         unreachable!();
     }
 
+    fn get_events(&self, idx: usize) -> &[Event] {
+        if idx == 3 { &self.events } else { &[] }
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        let Samples(time) = self.poll.to_samples(rate);
+        self.poll_time = time;
+        self.timer = self.timer.min(time);
+    }
+
     fn advance(&mut self) -> bool {
+        self.events.clear();
         if self.timer != 0 {
             self.timer -= 1;
             return !self.quit;
@@ -154,6 +182,21 @@ impl Module for Keyboard {
         } else {
             self.gate = false;
         }
+
+        // every key plays its own note for polyphonic voices
+        for (code, val) in self.keymap.iter() {
+            if newkeys.contains(code) && !self.held.iter().any(|(k, _)| k == code) {
+                let MidiNote(n) = Cent(self.oct + val).into();
+                self.events.push(Event::NoteOn(n.round() as u8, 1.0));
+                self.held.push((code.clone(), n.round() as u8));
+            }
+        }
+        for (code, n) in self.held.iter() {
+            if !newkeys.contains(code) {
+                self.events.push(Event::NoteOff(*n));
+            }
+        }
+        self.held.retain(|(k, _)| newkeys.contains(k));
         self.oldkeys = newkeys;
 
         return !self.quit;
@@ -161,6 +204,6 @@ impl Module for Keyboard {
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("keyboard", Keyboard::from_cmd);
+    l.register(Keyboard::descr(), Keyboard::from_cmd);
 }
 
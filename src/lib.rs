@@ -2,20 +2,29 @@
 pub mod additive;
 pub mod envelope;
 pub mod ascii;
+pub mod console;
 pub mod corr;
 pub mod delay;
+pub mod descr;
 pub mod file;
+pub mod fm;
 pub mod filt;
 pub mod flange;
 pub mod keyboard;
 pub mod loader;
+pub mod midifile;
 pub mod module;
+pub mod noise;
+pub mod osc;
 pub mod phaser;
 pub mod pitch;
+pub mod poly;
+pub mod render;
 pub mod resampler;
 pub mod simple;
 pub mod speaker;
 pub mod units;
 pub mod util;
 pub mod wav;
+pub mod wavetable;
 
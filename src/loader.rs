@@ -1,17 +1,118 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use crate::units::SAMPLE_RATE;
 use crate::module::*;
+use crate::render::{Recording, Capture, recording_new};
 
 type ParseFn = fn (&Vec<&str>) -> Result<ModRef, String>;
-type RegMap = HashMap<&'static str, ParseFn>;
+// Builds an instance out of other modules, running config lines in its scope.
+type ExpandFn = fn (&mut Expansion, &Vec<&str>) -> Result<(), String>;
+
+// How a registered module type is made from the normalized arguments of a line.
+#[derive(Clone, Copy)]
+enum Maker {
+    Module(ParseFn),
+    Expand(ExpandFn),
+}
+
+type RegMap = HashMap<String, (ModuleDescr, Maker)>;
 pub struct Loader {
     map: RegMap,
+    rate: f64, // sampling rate of loaded racks
+    capture: Option<Recording>, // speakers are replaced by captures when rendering offline
+    defs: HashMap<String, String>, // definition of each module of the last loaded rack
+    inits: HashMap<String, Vec<Option<f64>>>, // input values of each module of the last loaded rack as it was made
+    files: Vec<String>, // files read by the last load
+    session: Option<(Patch, Scope)>, // names defined by the last load, for lines run later with exec_line
+    console_lines: usize, // number of lines run with exec_line, for error messages
+    preloaded: HashMap<String, (PathBuf, String)>, // canonical path and text of files read ahead for exec_line
+}
+
+// A line of a config file, remembering where it came from for error messages.
+#[derive(Clone)]
+struct Line {
+    fname: String,
+    lno: usize,
+    text: String,
+}
+
+// A subpatch defined with "define name(args) ... end" and instantiated like a module.
+struct Subpatch {
+    descr: ModuleDescr,
+    body: Vec<Line>,
+}
+
+// Names visible to the lines being processed.
+// Each instance of a subpatch gets its own scope with its arguments as variables
+// and its name as a prefix for the modules it creates.
+#[derive(Clone, Default)]
+struct Scope {
+    prefix: String,
+    vars: HashMap<String, String>,
 }
 
-fn errstr(e: impl ToString) -> String {
-    e.to_string()
+// State built up while loading a config file.
+#[derive(Default)]
+struct Patch {
+    subpatches: HashMap<String, Subpatch>,
+    instances: HashSet<String>, // full names of subpatch instances
+    inputs: HashMap<String, Vec<(String, String)>>, // subpatch input "inst:name" to the inputs it drives
+    outputs: HashMap<String, (String, String)>, // subpatch output "inst:name" to the output providing it
+    including: Vec<PathBuf>, // files being included, to catch include loops
+    files: Vec<String>, // every file read
+
+    // type and arguments of each module, to find the ones that are unchanged on a reload
+    defs: HashMap<String, String>,
+    // input values of each module as it was made, which kept modules go back to on a reload
+    inits: HashMap<String, Vec<Option<f64>>>,
+    // when reloading, the running modules that can be kept if their definitions match
+    kept: HashMap<String, (String, ModRef, Vec<Option<f64>>)>,
+    adopted: HashSet<String>, // names of the kept modules
+    sets: Vec<(String, String, f64)>, // set lines for kept modules, applied when loading succeeds
+}
+
+// Put the arguments of a module line in the order of the descriptor, filling in defaults
+// and converting values with units to plain numbers.
+// Positional arguments come first, followed by any name=value arguments.
+// Frequencies can be at most the nyquist frequency, half the sampling rate of the rack.
+fn normalize_args(descr: &ModuleDescr, args: &[&str], rate: f64) -> Result<Vec<String>, String> {
+    let mut vals: Vec<Option<&str>> = vec![None; descr.args.len()];
+    let mut named = false;
+    for (n, arg) in args.iter().enumerate() {
+        if let Some((key, val)) = arg.split_once('=') {
+            let idx = descr.args.iter().position(|a| a.name == key)
+                .ok_or(format!("{} has no argument '{}', usage: {}", descr.name, key, descr.usage()))?;
+            if vals[idx].is_some() {
+                return Err(format!("argument '{}' given twice", key));
+            }
+            vals[idx] = Some(val);
+            named = true;
+        } else if named {
+            return Err(format!("positional argument '{}' after named arguments", arg));
+        } else if n < vals.len() {
+            vals[n] = Some(arg);
+        } else {
+            return Err(format!("usage: {}", descr.usage()));
+        }
+    }
+
+    let mut out = vec![descr.name.clone()];
+    for (a, val) in descr.args.iter().zip(vals) {
+        let val = match (val, &a.default) {
+            (Some(v), _) => v,
+            (None, Some(def)) => def.as_str(),
+            (None, None) => return Err(format!("missing argument '{}', usage: {}", a.name, descr.usage())),
+        };
+        let v = a.value(val)?;
+        if matches!(a.typ, ArgType::Float(Unit::Hz)) && v.parse::<f64>().is_ok_and(|hz| hz > rate / 2.0) {
+            return Err(format!("{} must be at most {} at a sample rate of {}, not {}", a.name, rate / 2.0, rate, val));
+        }
+        out.push(v);
+    }
+    Ok(out)
 }
 
 fn parse_terminal<'a>(name: &str, s: &'a str) -> Result<(&'a str, &'a str), String> {
@@ -23,9 +124,134 @@ fn parse_terminal<'a>(name: &str, s: &'a str) -> Result<(&'a str, &'a str), Stri
     }
 }
 
+fn is_ident(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Replace each $name in a word with the value of variable name.
+fn subst_vars(scope: &Scope, word: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = word;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos+1..];
+        let len = after.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(after.len());
+        let name = &after[..len];
+        let val = scope.vars.get(name).ok_or(format!("undefined variable '${}'", name))?;
+        out.push_str(val);
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Parse the "name(arg, arg=default, ...)" of a define line.
+fn parse_define(header: &str) -> Result<ModuleDescr, String> {
+    let (name, params) = match header.split_once('(') {
+        Some((name, rest)) => {
+            let params = rest.trim_end().strip_suffix(')').ok_or(format!("missing ')' in define '{}'", header.trim()))?;
+            (name.trim(), params)
+        },
+        None => (header.trim(), ""),
+    };
+    if !is_ident(name) {
+        return Err(format!("bad subpatch name '{}'", name));
+    }
+
+    let mut descr = ModuleDescr::new(name, "subpatch", "defined in a config file");
+    for param in params.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let arg = match param.split_once('=') {
+            Some((pname, def)) => ArgDescr::new(pname.trim(), ArgType::Str).default(def.trim()),
+            None => ArgDescr::new(param, ArgType::Str),
+        };
+        if !is_ident(&arg.name) {
+            return Err(format!("bad argument name '{}' for {}", arg.name, name));
+        }
+        descr = descr.arg(arg);
+    }
+    Ok(descr)
+}
+
+fn read_lines(fname: &str) -> Result<Vec<Line>, String> {
+    let file = File::open(fname).map_err(|e| format!("{}: {}", fname, e))?;
+    let mut lines = Vec::new();
+    for (lno, line_or_err) in BufReader::new(file).lines().enumerate() {
+        let text = line_or_err.map_err(|e| format!("{}: {}", fname, e))?;
+        lines.push(Line{ fname: fname.to_owned(), lno: lno+1, text });
+    }
+    Ok(lines)
+}
+
+fn text_lines(fname: &str, text: &str) -> Vec<Line> {
+    text.lines().enumerate().map(|(lno, text)| Line{ fname: fname.to_owned(), lno: lno+1, text: text.to_owned() }).collect()
+}
+
+fn split_words(line: &Line) -> Vec<&str> {
+    let mut ws: Vec<&str> = line.text.split_whitespace().collect();
+    if let Some(comment) = ws.iter().position(|s| s.starts_with('#')) {
+        ws.resize(comment, ""); // strip comments
+    }
+    ws
+}
+
+impl Patch {
+    // Follow subpatch outputs to the module output providing the value.
+    fn resolve_output(&self, m: &str, out: &str) -> Result<(String, String), String> {
+        let mut term = (m.to_owned(), out.to_owned());
+        while let Some(t) = self.outputs.get(&format!("{}:{}", term.0, term.1)) {
+            term = t.clone();
+        }
+        if self.instances.contains(&term.0) {
+            return Err(format!("{} has no output named {}", term.0, term.1));
+        }
+        Ok(term)
+    }
+
+    // Follow subpatch inputs to all of the module inputs they drive.
+    fn resolve_input(&self, m: &str, inp: &str) -> Result<Vec<(String, String)>, String> {
+        match self.inputs.get(&format!("{}:{}", m, inp)) {
+            Some(terms) => {
+                let mut res = Vec::new();
+                for (m2, inp2) in terms.iter() {
+                    res.extend(self.resolve_input(m2, inp2)?);
+                }
+                Ok(res)
+            },
+            None if self.instances.contains(m) => Err(format!("{} has no input named {}", m, inp)),
+            None => Ok(vec![(m.to_owned(), inp.to_owned())]),
+        }
+    }
+}
+
+// An instance being built by a registered module type that is made of other modules.
+// Lines run inside it name its modules and terminals without the instance prefix.
+pub struct Expansion<'a> {
+    loader: &'a mut Loader,
+    rack: &'a mut Rack,
+    patch: &'a mut Patch,
+    scope: Scope,
+    line: &'a Line, // the line being expanded, for error messages
+}
+
+impl Expansion<'_> {
+    // Run a config line inside the instance.
+    pub fn line(&mut self, text: String) -> Result<(), String> {
+        let line = Line{ fname: self.line.fname.clone(), lno: self.line.lno, text };
+        self.loader.proc_line(self.rack, self.patch, &mut self.scope, &line)
+    }
+
+    // Whether a module of the instance, or a subpatch instance, has an input.
+    pub fn has_input(&self, m: &str, inp: &str) -> bool {
+        let m = format!("{}{}", self.scope.prefix, m);
+        self.patch.resolve_input(&m, inp).map(|ins| ins.iter().all(|(m, inp)|
+            self.rack.get_terminals(m).map(|(ts, _)| ts.iter().any(|t| t.name == *inp)).unwrap_or(false))).unwrap_or(false)
+    }
+}
+
 impl Loader {
     pub fn new() -> Self {
-        let mut x = Self{ map: HashMap::new() };
+        let mut x = Self{ map: HashMap::new(), rate: SAMPLE_RATE, capture: None, defs: HashMap::new(), inits: HashMap::new(), files: Vec::new(),
+            session: None, console_lines: 0, preloaded: HashMap::new() };
         x.init();
         x
     }
@@ -38,80 +264,472 @@ impl Loader {
         crate::file::init(self);
         crate::filt::init(self);
         crate::flange::init(self);
+        crate::fm::init(self);
         crate::keyboard::init(self);
+        crate::midifile::init(self);
+        crate::noise::init(self);
         crate::phaser::init(self);
         crate::pitch::init(self);
+        crate::poly::init(self);
         crate::simple::init(self);
         crate::speaker::init(self);
         crate::util::init(self);
+        crate::wavetable::init(self);
     }
 
-    pub fn register(&mut self, name: &'static str, f: ParseFn) {
-        //println!("registered {}", name);
-        self.map.insert(name, f);
+    pub fn register(&mut self, descr: ModuleDescr, f: ParseFn) {
+        //println!("registered {}", descr.name);
+        self.map.insert(descr.name.clone(), (descr, Maker::Module(f)));
+    }
+
+    // Register a module type that is made of other modules, like a subpatch,
+    // such as poly with its copies of a voice.
+    pub fn register_expansion(&mut self, descr: ModuleDescr, f: ExpandFn) {
+        self.map.insert(descr.name.clone(), (descr, Maker::Expand(f)));
+    }
+
+    // Descriptors of all registered modules, sorted by name.
+    pub fn descriptors(&self) -> Vec<&ModuleDescr> {
+        let mut descrs: Vec<&ModuleDescr> = self.map.values().map(|(d, _)| d).collect();
+        descrs.sort_by_key(|d| &d.name);
+        descrs
+    }
+
+    pub fn describe(&self, name: &str) -> Result<&ModuleDescr, String> {
+        self.map.get(name).map(|(d, _)| d).ok_or(format!("unrecognized module '{}'", name))
+    }
+
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
+    }
+
+    // Load speakers as captures into the returned recording instead of opening the audio device.
+    pub fn capture_speakers(&mut self) -> Recording {
+        let rec = recording_new();
+        self.capture = Some(rec.clone());
+        rec
     }
 
     pub fn show_usage(&self) {
-        let mut mods: Vec<_> = self.map.iter().collect();
-        mods.sort_by_key(|(nm, _)| *nm);
-        for (name, newfunc) in mods.iter() {
-            // ugly hack to get usage -- provide way too many args.
-            // will keep working until some module can take arbitrary number of args
-            if let Err(msg) = newfunc(&vec![name; 32]) {
-                println!("  {}", msg.replace("usage: ", ""));
-            } else {
-                println!("  {} ???", name);
+        for descr in self.descriptors() {
+            println!("{}", descr.help());
+        }
+    }
+
+    // Descriptors of all registered modules as a JSON array, for editors.
+    pub fn to_json(&self) -> String {
+        let descrs: Vec<String> = self.descriptors().iter().map(|d| d.to_json()).collect();
+        format!("[\n{}\n]", descrs.join(",\n"))
+    }
+
+    // Wire an output to an input, optionally scaled by "*gain" and shifted by "+offset".
+    fn proc_wire(&mut self, rack: &mut Rack, patch: &Patch, scope: &Scope, args: Vec<String>) -> Result<(), String> {
+        if args.len() < 3 || args.len() > 5 {
+            return Err(format!("usage: wire module:output module:input [*gain] [+offset]"));
+        }
+        let (mod1,out) = parse_terminal("source", &args[1])?;
+        let (mod2,inp) = parse_terminal("dest", &args[2])?;
+        let (mod1, out) = patch.resolve_output(&format!("{}{}", scope.prefix, mod1), out)?;
+        for (mod2, inp) in patch.resolve_input(&format!("{}{}", scope.prefix, mod2), inp)? {
+            let (mut gain, mut offset) = (1.0, 0.0);
+            for arg in args[3..].iter() {
+                if let Some(g) = arg.strip_prefix('*') {
+                    gain = parse_value("gain", g, Unit::Gain)?;
+                } else if arg.starts_with('+') || arg.starts_with('-') {
+                    // offsets are in the units of the input they drive
+                    let (ins, _) = rack.get_terminals(&mod2)?;
+                    let t = ins.iter().find(|t| t.name == inp).ok_or(format!("{} has no input named {}", mod2, inp))?;
+                    offset = parse_value("offset", arg, t.unit)?;
+                } else {
+                    return Err(format!("expected *gain or +offset, not '{}'", arg));
+                }
             }
+            rack.add_scaled_wire(&mod1, &out, &mod2, &inp, gain, offset)?;
         }
+        Ok(())
     }
 
-    fn proc_wire(&mut self, rack: &mut Rack, args: Vec<&str>) -> Result<(), String> {
+    // Set the value of inputs that are not wired.
+    fn proc_set(&mut self, rack: &mut Rack, patch: &mut Patch, scope: &Scope, args: Vec<String>) -> Result<(), String> {
         if args.len() != 3 {
-            return Err(format!("wire needs two args"));
+            return Err(format!("usage: set module:input value"));
         }
-        let (mod1,out) = parse_terminal("source", args[1])?;
-        let (mod2,inp) = parse_terminal("dest", args[2])?;
-        rack.add_wire(mod1, out, mod2, inp)
+        let (m, inp) = parse_terminal("set", &args[1])?;
+        for (m, inp) in patch.resolve_input(&format!("{}{}", scope.prefix, m), inp)? {
+            let (ins, _) = rack.get_terminals(&m)?;
+            let t = ins.iter().find(|t| t.name == inp).ok_or(format!("{} has no input named {}", m, inp))?;
+            let val = parse_value(&inp, &args[2], t.unit)?;
+            if patch.adopted.contains(&m) {
+                // don't disturb a running module until the new patch is known to be good
+                patch.sets.push((m, inp, val));
+            } else {
+                rack.set_input(&m, &inp, val)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Declare a named input or output of the subpatch instance being loaded.
+    fn proc_port(&mut self, patch: &mut Patch, scope: &Scope, args: Vec<String>) -> Result<(), String> {
+        let inst = scope.prefix.strip_suffix('.').ok_or(format!("{} outside of a define", args[0]))?;
+        let is_input = args[0] == "input";
+        if args.len() < 3 || (!is_input && args.len() != 3) {
+            return Err(format!("usage: {} name module:terminal{}", args[0], if is_input { " ..." } else { "" }));
+        }
+        let port = format!("{}:{}", inst, args[1]);
+        if patch.inputs.contains_key(&port) || patch.outputs.contains_key(&port) {
+            return Err(format!("redefinition of {}", port));
+        }
+
+        let mut terms = Vec::new();
+        for arg in args[2..].iter() {
+            let (m, t) = parse_terminal(&args[0], arg)?;
+            terms.push((format!("{}{}", scope.prefix, m), t.to_owned()));
+        }
+        if is_input {
+            patch.inputs.insert(port, terms);
+        } else {
+            patch.outputs.insert(port, terms.remove(0));
+        }
+        Ok(())
+    }
+
+    fn proc_include(&mut self, rack: &mut Rack, patch: &mut Patch, scope: &mut Scope, line: &Line, args: Vec<String>) -> Result<(), String> {
+        if args.len() != 2 {
+            return Err(format!("usage: include fname"));
+        }
+        // relative to the including file
+        let path = Path::new(&line.fname).parent().unwrap_or(Path::new("")).join(&args[1]);
+        let fname = path.to_string_lossy().into_owned();
+        let (canon, lines) = match self.preloaded.get(&fname) {
+            Some((canon, text)) => (canon.clone(), text_lines(&fname, text)),
+            None => (path.canonicalize().map_err(|e| format!("{}: {}", path.display(), e))?, read_lines(&fname)?),
+        };
+        if patch.including.contains(&canon) {
+            return Err(format!("{} includes itself", path.display()));
+        }
+
+        patch.files.push(fname);
+        patch.including.push(canon);
+        let res = self.proc_lines(rack, patch, scope, &lines);
+        patch.including.pop();
+        res
+    }
+
+    fn proc_define(&mut self, patch: &mut Patch, line: &Line, body: &[Line]) -> Result<(), String> {
+        let header = line.text.trim_start().strip_prefix("define").unwrap_or("");
+        let header = header.split('#').next().unwrap_or(""); // strip comments
+        let descr = parse_define(header)?;
+        if self.map.contains_key(&descr.name) || patch.subpatches.contains_key(&descr.name) {
+            return Err(format!("redefinition of module type {}", descr.name));
+        }
+        patch.subpatches.insert(descr.name.clone(), Subpatch{ descr, body: body.to_vec() });
+        Ok(())
     }
 
-    fn proc_mod(&mut self, name: &str, rack: &mut Rack, args: Vec<&str>) -> Result<(), String> {
-        if args.len() == 0 {
+    // Scope of a new subpatch instance, whose modules are named "name.module".
+    fn new_instance(rack: &Rack, patch: &mut Patch, scope: &Scope, name: &str) -> Result<Scope, String> {
+        let full_name = format!("{}{}", scope.prefix, name);
+        if patch.instances.contains(&full_name) || rack.has_module(&full_name) {
+            return Err(format!("redefinition of {}", full_name));
+        }
+        patch.instances.insert(full_name.clone());
+        let mut inner = scope.clone();
+        inner.prefix = format!("{}.", full_name);
+        Ok(inner)
+    }
+
+    // Load the body of a subpatch with its modules named "name.module".
+    fn instantiate(&mut self, rack: &mut Rack, patch: &mut Patch, scope: &Scope, name: &str, args: &[&str]) -> Result<(), String> {
+        let vals = normalize_args(&patch.subpatches[args[0]].descr, &args[1..], self.rate)?;
+        let mut inner = Self::new_instance(rack, patch, scope, name)?;
+        let sub = &patch.subpatches[args[0]];
+        for (a, val) in sub.descr.args.iter().zip(vals.into_iter().skip(1)) {
+            inner.vars.insert(a.name.clone(), val);
+        }
+
+        let body = sub.body.clone();
+        self.proc_lines(rack, patch, &mut inner, &body)
+    }
+
+    // Build an instance of a registered module type that is made of other modules.
+    fn instantiate_expansion(&mut self, rack: &mut Rack, patch: &mut Patch, scope: &Scope, line: &Line, name: &str, args: &[&str]) -> Result<(), String> {
+        let (descr, maker) = &self.map[args[0]];
+        let vals = normalize_args(descr, &args[1..], self.rate)?;
+        let expand = match maker {
+            Maker::Expand(f) => *f,
+            Maker::Module(_) => unreachable!("{} is a module", args[0]),
+        };
+        let inner = Self::new_instance(rack, patch, scope, name)?;
+        let mut x = Expansion{ loader: self, rack, patch, scope: inner, line };
+        expand(&mut x, &vals.iter().map(|s| s.as_str()).collect())
+    }
+
+    fn proc_mod(&mut self, rack: &mut Rack, patch: &mut Patch, scope: &Scope, line: &Line, name: &str, args: Vec<String>) -> Result<(), String> {
+        if args.is_empty() {
             return Err(format!("module name without module definition"));
         }
+        if name.contains(':') {
+            return Err(format!("bad module name '{}'", name));
+        }
+
+        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        if patch.subpatches.contains_key(args[0]) {
+            return self.instantiate(rack, patch, scope, name, &args);
+        }
+        if let Some((_, Maker::Expand(_))) = self.map.get(args[0]) {
+            return self.instantiate_expansion(rack, patch, scope, line, name, &args);
+        }
+
+        let full_name = format!("{}{}", scope.prefix, name);
+        if patch.instances.contains(&full_name) {
+            return Err(format!("redefinition of {}", full_name));
+        }
+
+        let normalized = match &self.capture {
+            Some(_) if args[0] == "speaker" => None,
+            _ => {
+                let (descr, _) = self.map.get(args[0]).ok_or(format!("unrecognized module '{}'", args[0]))?;
+                Some(normalize_args(descr, &args[1..], self.rate)?)
+            },
+        };
+        let def = match &normalized {
+            Some(nargs) => format!("{} {}", args[0], nargs.join(" ")),
+            None => args.join(" "),
+        };
+        patch.defs.insert(full_name.clone(), def.clone());
+
+        // a reload keeps running modules that haven't changed, along with their state
+        if let Some((old_def, m, inits)) = patch.kept.get(&full_name) {
+            if *old_def == def {
+                rack.add_running_module(&full_name, m.clone())?;
+                patch.inits.insert(full_name.clone(), inits.clone());
+                patch.adopted.insert(full_name);
+                return Ok(());
+            }
+        }
 
-        let newfunc = self.map.get(args[0]).ok_or(format!("unrecognized module '{}'", args[0]))?;
-        let m = newfunc(&args)?;
-        rack.add_module(name, m)
+        let m = match normalized {
+            Some(nargs) => {
+                let newfunc = match &self.map[args[0]] {
+                    (_, Maker::Module(f)) => *f,
+                    (_, Maker::Expand(_)) => unreachable!("{} is expanded", args[0]),
+                };
+                newfunc(&nargs.iter().map(|s| s.as_str()).collect())?
+            },
+            None => Capture::from_cmd(self.capture.as_ref().unwrap(), &args)?, // only speakers while capturing
+        };
+        let inits = {
+            let m = m.borrow();
+            (0 .. m.get_terminals().0.len()).map(|idx| m.get_input(idx)).collect()
+        };
+        patch.inits.insert(full_name.clone(), inits);
+        rack.add_module(&full_name, m)
     }
 
-    fn proc_line(&mut self, rack: &mut Rack, mut ws: Vec<&str>) -> Result<(), String> {
-        if ws.len() == 0 {
+    fn proc_line(&mut self, rack: &mut Rack, patch: &mut Patch, scope: &mut Scope, line: &Line) -> Result<(), String> {
+        let ws = split_words(line);
+        if ws.is_empty() {
             return Ok(());
         }
+        let mut ws = ws.iter().map(|w| subst_vars(scope, w)).collect::<Result<Vec<String>, String>>()?;
+        rack.set_origin(&format!("{}:{}", line.fname, line.lno));
 
-        if ws[0] == "wire" {
-            return self.proc_wire(rack, ws);
-        } else {
-            let name = ws.remove(0).to_owned(); // ws.len() > 0
-            return self.proc_mod(&name, rack, ws);
+        match ws[0].as_str() {
+            "wire" => self.proc_wire(rack, patch, scope, ws),
+            "set" => self.proc_set(rack, patch, scope, ws),
+            "input" | "output" => self.proc_port(patch, scope, ws),
+            "include" => self.proc_include(rack, patch, scope, line, ws),
+            "let" => {
+                if ws.len() != 4 || ws[2] != "=" || !is_ident(&ws[1]) {
+                    return Err(format!("usage: let name = value"));
+                }
+                let val = ws.remove(3);
+                scope.vars.insert(ws.remove(1), val);
+                Ok(())
+            },
+            "end" => Err(format!("end without define")),
+            _ => {
+                let name = ws.remove(0); // ws.len() > 0
+                self.proc_mod(rack, patch, scope, line, &name, ws)
+            },
+        }
+    }
+
+    fn proc_lines(&mut self, rack: &mut Rack, patch: &mut Patch, scope: &mut Scope, lines: &[Line]) -> Result<(), String> {
+        let mut n = 0;
+        while n < lines.len() {
+            let line = &lines[n];
+            n += 1;
+
+            let res = if split_words(line).first() == Some(&"define") {
+                // the body runs up to the matching end
+                let start = n;
+                let mut depth = 1;
+                while n < lines.len() && depth > 0 {
+                    match split_words(&lines[n]).first() {
+                        Some(&"define") => depth += 1,
+                        Some(&"end") => depth -= 1,
+                        _ => (),
+                    }
+                    n += 1;
+                }
+                if depth > 0 {
+                    Err(format!("define without end"))
+                } else {
+                    self.proc_define(patch, line, &lines[start .. n-1])
+                }
+            } else {
+                self.proc_line(rack, patch, scope, line)
+            };
+            res.map_err(|e| format!("{}:{}: {}", line.fname, line.lno, e))?;
+        }
+        Ok(())
+    }
+
+    fn load_patch(&mut self, fname: &str, mut patch: Patch) -> Result<Rack, String> {
+        let mut rack = Rack::new_at(self.rate);
+        if let Ok(canon) = Path::new(fname).canonicalize() {
+            patch.including.push(canon);
+        }
+        patch.files.push(fname.to_owned());
+        let mut scope = Scope::default();
+        let res = read_lines(fname).and_then(|lines| self.proc_lines(&mut rack, &mut patch, &mut scope, &lines));
+        self.files = patch.files.clone();
+        res?;
+
+        // kept modules are set up as if new, except for the state they keep
+        for name in patch.adopted.iter() {
+            let m = rack.module(name)?;
+            let mut m = m.borrow_mut();
+            for (idx, init) in patch.inits[name].iter().enumerate() {
+                if let Some(v) = *init {
+                    if !rack.input_wired(name, idx) && m.get_input(idx) != Some(v) {
+                        m.set_input(idx, v);
+                    }
+                }
+            }
         }
+        for (m, inp, val) in patch.sets.drain(..) {
+            rack.set_input(&m, &inp, val)?;
+        }
+        rack.set_origin("");
+        self.defs = patch.defs.clone();
+        self.inits = patch.inits.clone();
+        patch.kept.clear();
+        patch.adopted.clear();
+        self.session = Some((patch, scope));
+        Ok(rack)
+    }
+
+    // Give the text of a file that the next line run with exec_line includes,
+    // read ahead of time so that running the line doesn't wait on the disk.
+    // fname is the path as the include line resolves it, and canon its canonical path.
+    pub fn preload(&mut self, fname: &str, canon: PathBuf, text: String) {
+        self.preloaded.insert(fname.to_owned(), (canon, text));
+    }
+
+    // Run a single config line against a rack, such as one typed while the rack plays.
+    // Variables and subpatches defined by the last loaded config can be used.
+    pub fn exec_line(&mut self, rack: &mut Rack, text: &str) -> Result<(), String> {
+        self.console_lines += 1;
+        let line = Line{ fname: "console".to_string(), lno: self.console_lines, text: text.to_owned() };
+        if split_words(&line).first() == Some(&"define") {
+            return Err(format!("define needs several lines, use include instead"));
+        }
+        let (mut patch, mut scope) = self.session.take().unwrap_or_default();
+        let res = self.proc_line(rack, &mut patch, &mut scope, &line);
+        rack.set_origin("");
+        self.session = Some((patch, scope));
+        self.preloaded.clear();
+        res
     }
 
     pub fn load(&mut self, fname: &str) -> Result<Rack, String> {
-        let file = File::open(fname).map_err(errstr)?;
+        self.load_patch(fname, Patch::default())
+    }
 
-        let mut rack = Rack::new();
-        for (lno, line_or_err) in BufReader::new(file).lines().enumerate() {
-            let line = line_or_err.map_err(|e| format!("{}: {}", fname, e))?;
+    // Load a config file again as a new version of a rack loaded earlier by this loader.
+    // Modules whose type and arguments are unchanged are moved to the new rack as they
+    // are, keeping their state, and only new or changed modules are created.
+    // The running rack isn't changed, so it can keep playing if loading fails.
+    pub fn reload(&mut self, rack: &Rack, fname: &str) -> Result<Rack, String> {
+        let mut patch = Patch::default();
+        for (name, def) in self.defs.iter() {
+            if let Ok(m) = rack.module(name) {
+                patch.kept.insert(name.clone(), (def.clone(), m, self.inits.get(name).cloned().unwrap_or_default()));
+            }
+        }
+        self.load_patch(fname, patch)
+    }
 
-            // strip comments
-            let mut ws: Vec<&str> = line.split_whitespace().collect();
-            if let Some(comment) = ws.iter().position(|s| s.starts_with('#')) {
-                ws.resize(comment, ""); // strip comments
+    // Config files read by the last load, including the ones it included.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
+    use crate::units::Samples;
+
+    const BEFORE: &str = "src const 2
+lfo osc2 sin 5
+m mult
+f filter lp 1000
+wire src:out m:in1
+wire lfo:out m:in2
+wire m:out f:in
+set f:freq 500
+set lfo:width 0.3
+";
+
+    const AFTER: &str = "src const 2
+lfo osc2 sin 5
+m mult
+f filter lp 1000
+wire src:out m:in1
+wire m:out f:in
+set lfo:width 0.3
+";
+
+    // Values of the inputs that no wire drives.
+    fn unwired_inputs(rack: &Rack) -> Vec<(String, String, f64)> {
+        let mut vals = Vec::new();
+        for name in rack.module_names() {
+            let (ins, _) = rack.get_terminals(name).unwrap();
+            for (idx, t) in ins.into_iter().enumerate() {
+                if rack.input_wired(name, idx) {
+                    continue;
+                }
+                if let Ok(v) = rack.get_input(name, &t.name) {
+                    vals.push((name.to_string(), t.name, v));
+                }
             }
+        }
+        vals
+    }
 
-            self.proc_line(&mut rack, ws).map_err(|e| format!("{}:{}: {}", fname, lno+1, e))?;
+    #[test]
+    fn reloaded_modules_lose_removed_wires_and_sets() {
+        let fname = env::temp_dir().join("rau_reload_test.rau").to_string_lossy().into_owned();
+        fs::write(&fname, BEFORE).unwrap();
+        let mut l = Loader::new();
+        let mut rack = l.load(&fname).unwrap();
+        rack.run(Samples(1000));
+        assert_ne!(rack.get_input("m", "in2").unwrap(), 1.0);
+        assert_eq!(rack.get_input("f", "freq").unwrap(), 500.0);
+
+        fs::write(&fname, AFTER).unwrap();
+        let reloaded = l.reload(&rack, &fname).unwrap();
+        for name in ["src", "lfo", "m", "f"] {
+            assert!(Rc::ptr_eq(&rack.module(name).unwrap(), &reloaded.module(name).unwrap()), "{} was remade", name);
         }
-        Ok(rack)
+        let fresh = Loader::new().load(&fname).unwrap();
+        assert_eq!(unwired_inputs(&reloaded), unwired_inputs(&fresh));
     }
 }
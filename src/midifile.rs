@@ -0,0 +1,505 @@
+use std::fs;
+use std::str::FromStr;
+use crate::units::{Cent, Hz, MidiNote, SAMPLE_RATE};
+use crate::module::*;
+
+// reference: https://www.midi.org/specifications/file-format-specifications/standard-midi-files
+
+const CHANNELS: usize = 16;
+const DEFAULT_TEMPO: f64 = 500000.0; // microseconds per quarter note, 120 bpm
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ending {
+    Stop, // ask the rack to shut down
+    Loop, // start over
+    Hold, // keep running without notes
+}
+
+pub const ENDINGS: &[&str] = &["stop", "loop", "hold"];
+
+impl Ending {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ending::Stop => "stop",
+            Ending::Loop => "loop",
+            Ending::Hold => "hold",
+        }
+    }
+}
+
+impl FromStr for Ending {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stop" { return Ok(Ending::Stop); }
+        if s == "loop" { return Ok(Ending::Loop); }
+        if s == "hold" { return Ok(Ending::Hold); }
+        Err(format!("unrecognized ending '{}'", s))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    NoteOn(u8, u8), // note, velocity
+    NoteOff(u8),
+    Control(u8, u8), // controller number, value
+    Clock, // 24 per quarter note
+}
+
+// A channel message at a time in seconds from the start of the song.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SongEvent {
+    pub time: f64,
+    pub chan: usize,
+    pub kind: Kind,
+}
+
+// The events of all tracks of a standard midi file, in the order they play.
+#[derive(Clone, Debug, Default)]
+pub struct Song {
+    pub events: Vec<SongEvent>,
+    pub length: f64, // seconds up to the end of the longest track
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err(format!("truncated at byte {}", self.pos));
+        }
+        let bytes = &self.buf[self.pos .. self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // variable length quantity, seven bits per byte with the high bit set on all but the last
+    fn vlq(&mut self) -> Result<u32, String> {
+        let mut v = 0;
+        for _ in 0 .. 4 {
+            let b = self.byte()?;
+            v = (v << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(format!("bad variable length number at byte {}", self.pos))
+    }
+
+    // A chunk's type and contents.
+    fn chunk(&mut self) -> Result<(&'a [u8], Cursor<'a>), String> {
+        let typ = self.take(4)?;
+        let len = self.u32()? as usize;
+        Ok((typ, Cursor{ buf: self.take(len)?, pos: 0 }))
+    }
+}
+
+// Events of a track at their tick, tempo changes, and the tick the track ends on.
+type Track = (Vec<(u64, usize, Kind)>, Vec<(u64, f64)>, u64);
+
+fn parse_track(mut c: Cursor) -> Result<Track, String> {
+    let mut events = Vec::new();
+    let mut tempos = Vec::new();
+    let mut tick: u64 = 0;
+    let mut status = 0;
+    while c.pos < c.buf.len() {
+        tick += c.vlq()? as u64;
+        let mut b = c.byte()?;
+        if b < 0x80 {
+            // running status, the byte is the first data byte
+            if status == 0 {
+                return Err(format!("data byte without a status at byte {}", c.pos));
+            }
+            c.pos -= 1;
+            b = status;
+        }
+        match b {
+            0xff => {
+                status = 0;
+                let typ = c.byte()?;
+                let len = c.vlq()? as usize;
+                let data = c.take(len)?;
+                match typ {
+                    0x2f => break, // end of track
+                    0x51 if len == 3 => tempos.push((tick, ((data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32) as f64)),
+                    _ => (),
+                }
+            },
+            0xf0 | 0xf7 => {
+                status = 0;
+                let len = c.vlq()? as usize;
+                c.take(len)?; // system exclusive
+            },
+            0x80 ..= 0xef => {
+                status = b;
+                let chan = (b & 0x0f) as usize;
+                match b & 0xf0 {
+                    0x80 => {
+                        let note = c.byte()?;
+                        c.byte()?;
+                        events.push((tick, chan, Kind::NoteOff(note)));
+                    },
+                    0x90 => {
+                        let (note, vel) = (c.byte()?, c.byte()?);
+                        let kind = if vel == 0 { Kind::NoteOff(note) } else { Kind::NoteOn(note, vel) };
+                        events.push((tick, chan, kind));
+                    },
+                    0xb0 => {
+                        let (num, val) = (c.byte()?, c.byte()?);
+                        events.push((tick, chan, Kind::Control(num, val)));
+                    },
+                    0xc0 | 0xd0 => { c.byte()?; },
+                    _ => { c.take(2)?; }, // aftertouch and pitch bend
+                }
+            },
+            _ => return Err(format!("unexpected status {:#x} at byte {}", b, c.pos)),
+        }
+    }
+    Ok((events, tempos, tick))
+}
+
+impl Song {
+    // Parse a type 0 or type 1 standard midi file.
+    pub fn parse(buf: &[u8]) -> Result<Self, String> {
+        let mut c = Cursor{ buf, pos: 0 };
+        let (typ, mut hdr) = c.chunk()?;
+        if typ != b"MThd" {
+            return Err(format!("not a midi file"));
+        }
+        let format = hdr.u16()?;
+        let ntracks = hdr.u16()?;
+        let division = hdr.u16()?;
+        if format > 1 {
+            return Err(format!("midi file format {} isn't supported", format));
+        }
+        if division & 0x7fff == 0 || (division & 0x8000 != 0 && division & 0xff == 0) {
+            return Err(format!("midi file division {:#x} has no ticks", division));
+        }
+
+        let mut events = Vec::new();
+        let mut tempos = Vec::new();
+        let mut end = 0;
+        let mut found = 0;
+        while found < ntracks && c.pos < c.buf.len() {
+            let (typ, chunk) = c.chunk()?;
+            if typ != b"MTrk" {
+                continue; // unknown chunks are skipped
+            }
+            let (evs, ts, track_end) = parse_track(chunk).map_err(|e| format!("track {}: {}", found, e))?;
+            events.extend(evs);
+            tempos.extend(ts);
+            end = end.max(track_end);
+            found += 1;
+        }
+        // midi clock, when ticks are parts of a quarter note
+        if division & 0x8000 == 0 {
+            let clocks = end.checked_mul(24).ok_or(format!("midi file is too long"))?
+                .div_ceil(division as u64); // up to the end, where a loop starts again
+            events.extend((0 .. clocks).map(|n| (n * division as u64 / 24, 0, Kind::Clock)));
+        }
+
+        // tracks are merged keeping the order of events at the same tick
+        events.sort_by_key(|&(tick, _, _)| tick);
+        tempos.sort_by_key(|&(tick, _)| tick);
+
+        // seconds at each tick, following tempo changes
+        let to_sec: Box<dyn Fn(u64) -> f64> = if division & 0x8000 != 0 {
+            // frames per second and ticks per frame, and no tempo
+            let fps = match ((division >> 8) as u8 as i8).wrapping_neg() {
+                29 => 29.97,
+                fps => fps as f64,
+            };
+            let per_frame = (division & 0xff) as f64;
+            Box::new(move |tick| tick as f64 / (fps * per_frame))
+        } else {
+            let per_quarter = division as f64;
+            let mut map = vec![(0, 0.0, DEFAULT_TEMPO)]; // tick, seconds, tempo from then on
+            for (tick, tempo) in tempos {
+                let (t0, s0, tempo0) = *map.last().unwrap();
+                map.push((tick, s0 + (tick - t0) as f64 * tempo0 / 1e6 / per_quarter, tempo));
+            }
+            Box::new(move |tick| {
+                let &(t0, s0, tempo) = map.iter().rev().find(|(t, _, _)| *t <= tick).unwrap();
+                s0 + (tick - t0) as f64 * tempo / 1e6 / per_quarter
+            })
+        };
+
+        Ok(Song{
+            events: events.iter().map(|&(tick, chan, kind)| SongEvent{ time: to_sec(tick), chan, kind }).collect(),
+            length: to_sec(end),
+        })
+    }
+
+    pub fn load(fname: &str) -> Result<Self, String> {
+        let buf = fs::read(fname).map_err(|e| format!("{}: {}", fname, e))?;
+        Self::parse(&buf).map_err(|e| format!("{}: {}", fname, e))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Channel {
+    held: Vec<u8>, // notes held down, the latest last
+    pitch: f64,
+    vel: f64,
+    cc: f64,
+    events: Vec<Event>, // sent on this sample
+}
+
+pub struct MidiFile {
+    fname: String,
+    ending: Ending,
+    cc: u8, // controller number shown on the cc outputs
+    song: Song,
+
+    rate: f64,
+    pos: usize, // samples since the start of the song
+    next: usize, // next event to play
+    chans: Vec<Channel>,
+    clock: Vec<Event>, // clock sent on this sample
+}
+
+fn outputs() -> Vec<TerminalDescr> {
+    let mut outs = Vec::new();
+    for ch in 1 ..= CHANNELS {
+        outs.push(TerminalDescr::new(&format!("pitch{}", ch), Unit::Hz).descr("frequency of the latest note"));
+        outs.push(TerminalDescr::new(&format!("gate{}", ch), Unit::Gate).descr("high while a note is held"));
+        outs.push(TerminalDescr::new(&format!("vel{}", ch), Unit::Gain).range(0.0, 1.0).descr("velocity of the latest note"));
+        outs.push(TerminalDescr::new(&format!("cc{}", ch), Unit::Value).range(0.0, 1.0).descr("value of the chosen controller"));
+        outs.push(TerminalDescr::new(&format!("notes{}", ch), Unit::Event).descr("note and controller events"));
+    }
+    outs.push(TerminalDescr::new("clock", Unit::Event).descr("24 clock events per quarter note"));
+    outs
+}
+
+impl MidiFile {
+    pub fn descr() -> ModuleDescr {
+        let mut d = ModuleDescr::new("midifile", "source", "play the notes of a standard midi file, on outputs for each channel")
+            .arg(ArgDescr::new("fname", ArgType::Str).descr("type 0 or 1 midi file"))
+            .arg(ArgDescr::new("end", ArgType::choice(ENDINGS)).default("stop").descr("stop the rack, loop or hold at the end of the song"))
+            .arg(ArgDescr::new("cc", ArgType::Int).range(0.0, 127.0).default("1").descr("controller number for the cc outputs"));
+        for t in outputs() {
+            d = d.output(t);
+        }
+        d
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 4 {
+            return Err(format!("usage: {} fname end cc", args[0]));
+        }
+        let ending = parse::<Ending>("end", args[2])?;
+        let cc = parse::<u8>("cc", args[3])?;
+        let song = Song::load(args[1])?;
+        Ok( modref_new(Self::new(args[1], song, ending, cc)) )
+    }
+
+    pub fn new(fname: &str, song: Song, ending: Ending, cc: u8) -> Self {
+        MidiFile {
+            fname: fname.to_owned(),
+            ending,
+            cc,
+            song,
+            rate: SAMPLE_RATE,
+            pos: 0,
+            next: 0,
+            chans: vec![Channel::default(); CHANNELS],
+            clock: Vec::new(),
+        }
+    }
+
+    fn play(&mut self, ev: SongEvent) {
+        if ev.kind == Kind::Clock {
+            self.clock.push(Event::Clock);
+            return;
+        }
+        let ch = &mut self.chans[ev.chan];
+        match ev.kind {
+            Kind::NoteOn(note, vel) => {
+                ch.held.retain(|&n| n != note);
+                ch.held.push(note);
+                let Hz(freq) = Cent::from(MidiNote(note as f64)).into();
+                ch.pitch = freq;
+                ch.vel = vel as f64 / 127.0;
+                ch.events.push(Event::NoteOn(note, ch.vel));
+            },
+            Kind::NoteOff(note) => {
+                ch.held.retain(|&n| n != note);
+                ch.events.push(Event::NoteOff(note));
+                // fall back to a note that is still held, like a mono synth
+                if let Some(&last) = ch.held.last() {
+                    let Hz(freq) = Cent::from(MidiNote(last as f64)).into();
+                    ch.pitch = freq;
+                }
+            },
+            Kind::Control(num, val) => {
+                if num == self.cc {
+                    ch.cc = val as f64 / 127.0;
+                }
+                ch.events.push(Event::Control(num, val as f64 / 127.0));
+            },
+            Kind::Clock => (),
+        }
+    }
+}
+
+impl Module for MidiFile {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        MidiFile::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["midifile".to_string(), self.fname.clone(), self.ending.name().to_string(), self.cc.to_string()]
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        let ch = self.chans.get(idx / 5)?;
+        match idx % 5 {
+            0 => Some(ch.pitch),
+            1 => Some(if ch.held.is_empty() { 0.0 } else { 1.0 }),
+            2 => Some(ch.vel),
+            3 => Some(ch.cc),
+            _ => None,
+        }
+    }
+
+    fn set_input(&mut self, _idx: usize, _value: f64) {
+        unreachable!();
+    }
+
+    fn get_events(&self, idx: usize) -> &[Event] {
+        match self.chans.get(idx / 5) {
+            Some(ch) if idx % 5 == 4 => &ch.events,
+            Some(_) => &[],
+            None => &self.clock,
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        // stay at the same time in the song
+        self.pos = (self.pos as f64 * rate / self.rate).round() as usize;
+        self.rate = rate;
+    }
+
+    fn advance(&mut self) -> bool {
+        // the song takes samples 0 to end, and a loop starts over on sample end
+        let end = ((self.song.length * self.rate).round() as usize).max(1);
+        for ch in self.chans.iter_mut() {
+            ch.events.clear();
+        }
+        self.clock.clear();
+        if self.pos >= end && self.ending == Ending::Loop {
+            for ch in self.chans.iter_mut() {
+                for n in ch.held.drain(..) {
+                    ch.events.push(Event::NoteOff(n));
+                }
+            }
+            self.pos = 0;
+            self.next = 0;
+        }
+
+        // events play on the sample nearest to their time
+        while let Some(&ev) = self.song.events.get(self.next) {
+            if (ev.time * self.rate).round() as usize > self.pos {
+                break;
+            }
+            self.play(ev);
+            self.next += 1;
+        }
+        self.pos += 1;
+        !(self.ending == Ending::Stop && self.pos > end)
+    }
+}
+
+pub fn init(l: &mut Loader) {
+    l.register(MidiFile::descr(), MidiFile::from_cmd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A type 0 file with one track.
+    fn smf(division: u16, track: &[u8]) -> Vec<u8> {
+        let mut buf = b"MThd\0\0\0\x06\0\0\0\x01".to_vec();
+        buf.extend_from_slice(&division.to_be_bytes());
+        buf.extend_from_slice(b"MTrk");
+        buf.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        buf.extend_from_slice(track);
+        buf
+    }
+
+    // A file with one track holding only its end.
+    fn file(division: u16) -> Vec<u8> {
+        smf(division, b"\x60\xff\x2f\0")
+    }
+
+    // 96 ticks per quarter note, at 120 bpm and then 240 bpm.
+    const SONG: &[u8] = &[
+        0x18, 0x90, 60, 100, // tick 24, 0.125s: note on
+        0x18, 60, 0, // tick 48, 0.25s: running status, off as a note on without velocity
+        0x30, 0xff, 0x51, 3, 0x03, 0xd0, 0x90, // tick 96, 0.5s: 250000us per quarter note
+        0x00, 0x90, 64, 80, // tick 96: note on
+        0x30, 67, 96, // tick 144, 0.625s: running status, another note on
+        0x18, 0x80, 67, 0, // tick 168, 0.6875s: note off, back to the held note
+        0x18, 64, 0, // tick 192, 0.75s: running status, note off as the song ends
+        0x00, 0xff, 0x2f, 0,
+    ];
+
+    fn note_hz(note: u8) -> f64 {
+        Hz::from(Cent::from(MidiNote(note as f64))).0
+    }
+
+    #[test]
+    fn division_without_ticks_is_an_error() {
+        assert!(Song::parse(&file(96)).is_ok());
+        for division in [0, 0x8000, 0xe700] {
+            let e = Song::parse(&file(division)).err().unwrap();
+            assert!(e.contains("division"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn outputs_change_on_the_samples_of_their_events() {
+        let song = Song::parse(&smf(96, SONG)).unwrap();
+        assert_eq!(song.length, 0.75);
+        let mut m = MidiFile::new("song.mid", song, Ending::Loop, 1);
+
+        let (mut gates, mut pitches, mut wrap_events) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut gate, mut pitch) = (0.0, 0.0);
+        for n in 0 .. 43000 {
+            assert!(Module::advance(&mut m));
+            let (g, p) = (m.get_output(1).unwrap(), m.get_output(0).unwrap());
+            if g != gate {
+                gates.push((n, g));
+            }
+            if p != pitch {
+                pitches.push((n, p));
+            }
+            if n == 36000 {
+                wrap_events = m.get_events(4).to_vec();
+            }
+            (gate, pitch) = (g, p);
+        }
+
+        // the song is 36000 samples, so the loop plays its first note again on sample 42000
+        assert_eq!(gates, vec![(6000, 1.0), (12000, 0.0), (24000, 1.0), (36000, 0.0), (42000, 1.0)]);
+        assert_eq!(pitches, vec![(6000, note_hz(60)), (24000, note_hz(64)), (30000, note_hz(67)), (33000, note_hz(64)), (42000, note_hz(60))]);
+        assert_eq!(wrap_events, vec![Event::NoteOff(64)]);
+    }
+}
@@ -2,14 +2,122 @@
 use std::str::FromStr;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::convert::Into;
-use crate::units::Samples;
+use std::fs;
+use std::fmt;
+use std::collections::HashMap;
+use crate::units::{Samples, SAMPLE_RATE};
 pub use crate::loader::*;
+pub use crate::descr::*;
+
+// Kind of value carried by a terminal.
+// Event terminals carry events instead of a value on every sample.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Unit { Hz, Sec, Gain, Db, Gate, Audio, Value, Event }
+
+impl Unit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Unit::Hz => "Hz",
+            Unit::Sec => "seconds",
+            Unit::Gain => "gain",
+            Unit::Db => "dB",
+            Unit::Gate => "gate",
+            Unit::Audio => "audio",
+            Unit::Value => "value",
+            Unit::Event => "events",
+        }
+    }
+
+    // range of values that makes sense for the unit
+    fn range(&self) -> (f64, f64) {
+        match self {
+            Unit::Hz | Unit::Sec => (0.0, f64::INFINITY),
+            Unit::Gate => (0.0, 1.0),
+            Unit::Event => (0.0, 0.0),
+            Unit::Gain | Unit::Db | Unit::Audio | Unit::Value => (f64::NEG_INFINITY, f64::INFINITY),
+        }
+    }
+}
+
+// Description of a terminal on a module
+#[derive(Clone, Debug)]
+pub struct TerminalDescr {
+    pub name: String,
+    pub unit: Unit,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64, // value of an input when nothing drives it
+    pub required: bool, // an input that does nothing useful unless wired or set
+    pub descr: String,
+}
+
+impl TerminalDescr {
+    pub fn new(name: &str, unit: Unit) -> Self {
+        let (min, max) = unit.range();
+        TerminalDescr {
+            name: name.to_owned(),
+            unit,
+            min,
+            max,
+            default: 0.0_f64.clamp(min, max),
+            required: false,
+            descr: String::new(),
+        }
+    }
+
+    pub fn range(mut self, min: f64, max: f64) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    pub fn default(mut self, v: f64) -> Self {
+        self.default = v;
+        self
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn descr(mut self, descr: &str) -> Self {
+        self.descr = descr.to_owned();
+        self
+    }
+
+    pub fn in_range(&self, v: f64) -> bool {
+        self.min <= v && v <= self.max
+    }
+
+    pub fn check(&self, mod_name: &str, v: f64) -> Result<(), String> {
+        if self.in_range(v) {
+            Ok(())
+        } else {
+            Err(format!("{}'s {} must be between {} and {}, not {}", mod_name, self.name, self.min, self.max, v))
+        }
+    }
+}
 
-// Description of a terminals on a module
-// XXX for now
-pub type TerminalDescr = String;
+// Something that happens at a sample, sent from an event output to the event inputs
+// wired to it. Unlike values, any number of events can happen on the same sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    NoteOn(u8, f64), // midi note and velocity from 0 to 1
+    NoteOff(u8),
+    Control(u8, f64), // controller number and value from 0 to 1
+    Trigger,
+    Clock, // 24 per quarter note, like midi clock
+}
+
+// An event on terminal term at sample time within a block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timed {
+    pub time: usize,
+    pub term: usize,
+    pub event: Event,
+}
 
 // Modules need to be wrapped somehow because they are "dyn".
 // Using reference counting simplifies storing modules in wires in a rack (but is not strictly necessary).
@@ -19,14 +127,6 @@ pub fn modref_new<T: 'static + Module>(data: T) -> ModRef {
     Rc::new( RefCell::new(data) )
 }
 
-// proposed
-#[allow(dead_code)]
-pub struct TerminalDescr2 {
-    name: String,
-    min: f64,
-    max: f64,
-}
-
 /*
  * Interface to modules with inputs and outputs and a sample-based clock.
  */
@@ -44,130 +144,949 @@ pub trait Module {
     // advance the clock by one sample, return false to request shutdown
     fn advance(&mut self) -> bool;
 
-    // XXX some sort of interface for getting generic parameters
-    // and setting them from ascii strings?
+    // Receive an event on event input idx, before advance is called for the sample it happens on.
+    fn send_event(&mut self, _idx: usize, _event: Event) {
+    }
+
+    // Events sent from event output idx by the last call to advance.
+    fn get_events(&self, _idx: usize) -> &[Event] {
+        &[]
+    }
+
+    // Process a block of len samples at once, return false to request shutdown.
+    // inputs[i] holds len values for input terminal i, or is empty if nothing
+    // drives that input, in which case the input keeps its current value.
+    // outputs[i] receives len values for output terminal i.
+    // events holds the events arriving on event inputs, in order of time, and
+    // the events sent from event outputs are added to out_events.
+    // The default steps through the block with the per-sample methods.
+    fn process_block(&mut self, len: usize, inputs: &[&[f64]], outputs: &mut [&mut [f64]],
+                     events: &[Timed], out_events: &mut Vec<Timed>) -> bool {
+        let mut keep_running = true;
+        let mut next = 0;
+        for n in 0 .. len {
+            for (idx, inp) in inputs.iter().enumerate() {
+                if !inp.is_empty() {
+                    self.set_input(idx, inp[n]);
+                }
+            }
+            while next < events.len() && events[next].time <= n {
+                self.send_event(events[next].term, events[next].event);
+                next += 1;
+            }
+            let ok = self.advance();
+            keep_running = keep_running && ok;
+            for (idx, out) in outputs.iter_mut().enumerate() {
+                out[n] = self.get_output(idx).unwrap_or(0.0);
+                for &event in self.get_events(idx) {
+                    out_events.push(Timed{ time: n, term: idx, event });
+                }
+            }
+        }
+        keep_running
+    }
+
+    // True for modules whose output carries their input from earlier samples,
+    // such as delay lines, so that a feedback loop through them is intended.
+    fn has_delay(&self) -> bool {
+        false
+    }
+
+    // Called with the rack's sampling rate when the module is added to a rack
+    // and whenever the rate changes. Modules that derive coefficients or buffer
+    // sizes from the rate recompute them here. Modules start out at SAMPLE_RATE.
+    fn set_sample_rate(&mut self, _rate: f64) {
+    }
+
+    // Module type and the arguments that would construct the module as it is now,
+    // as written on a config line. Used to save racks.
+    fn get_args(&self) -> Vec<String>;
+
+    // Current value at input terminal idx, if the module keeps it.
+    fn get_input(&self, _idx: usize) -> Option<f64> {
+        None
+    }
 
     fn input_idx(&self, mod_name: &str, name: &str) -> Result<usize, String> {
         let (ins, _) = self.get_terminals();
-        ins.iter().position(|n| n.eq(name))
+        ins.iter().position(|t| t.name == name)
                 .ok_or(format!("{} has no input named {}", mod_name, name))
     }
 
     fn output_idx(&self, mod_name: &str, name: &str) -> Result<usize, String> {
         let (_, outs) = self.get_terminals();
-        outs.iter().position(|n| n.eq(name))
+        outs.iter().position(|t| t.name == name)
                 .ok_or(format!("{} has no output named {}", mod_name, name))
     }
 
+    // Set an input by name, rejecting values outside of the input's range.
     fn set_named_input(&mut self, mod_name: &str, name: &str, val: f64) -> Result<(), String> {
         let idx = self.input_idx(mod_name, name)?;
-        // XXX set_input should report errors
+        let (ins, _) = self.get_terminals();
+        if ins[idx].unit == Unit::Event {
+            return Err(format!("{}'s {} takes events, not values", mod_name, name));
+        }
+        ins[idx].check(mod_name, val)?;
         self.set_input(idx, val);
         Ok(())
     }
 
     fn get_named_output(&self, mod_name: &str, name: &str) -> Result<f64, String> {
         let idx = self.output_idx(mod_name, name)?;
+        if self.get_terminals().1[idx].unit == Unit::Event {
+            return Err(format!("{}'s {} sends events, not values", mod_name, name));
+        }
         self.get_output(idx).ok_or(format!("can't set {}", name))
     }
 }
 
-// Connection from module inputs to module outputs
+// Connection from module outputs to module inputs.
+// The output is scaled by gain and shifted by offset on its way.
 struct Wire {
-    from_mod: ModRef,
+    from_mod_name: String,
     from_out: usize,
 
     to_mod_name: String,
-    to_mod: ModRef,
+    to_in: usize,
+
+    gain: f64,
+    offset: f64,
+    origin: String, // where the wire was defined, if known
+}
+
+// Range of values a wire can carry from an output with the given range.
+fn scaled_range(min: f64, max: f64, gain: f64, offset: f64) -> (f64, f64) {
+    if gain == 0.0 {
+        return (offset, offset); // avoid 0 * inf
+    }
+    let (a, b) = (min * gain + offset, max * gain + offset);
+    (a.min(b), a.max(b))
+}
+
+// Config text for a wire's gain and offset, empty when they do nothing.
+fn scale_text(gain: f64, offset: f64) -> String {
+    let mut s = String::new();
+    if gain != 1.0 {
+        s.push_str(&format!(" *{}", gain));
+    }
+    if offset != 0.0 {
+        s.push_str(&format!(" {}{}", if offset > 0.0 { "+" } else { "" }, offset));
+    }
+    s
+}
+
+// A wire resolved to module positions, for use while running.
+struct Feed {
+    wire: usize, // index into the rack's wires
+    from: usize,
+    from_out: usize,
+    gain: f64,
+    offset: f64,
+}
+
+// A wire into an event input, which passes on every event of its source.
+struct EventFeed {
+    wire: usize,
+    from: usize,
+    from_out: usize,
     to_in: usize,
 }
 
+// A wired input, driven by the sum of its wires.
+struct Input {
+    to_in: usize,
+    feeds: Vec<Feed>,
+    clamp: Option<(f64, f64)>, // when the sum's range exceeds the input's range
+    buf: Vec<f64>, // summed values when running in blocks, unless a single wire passes through unchanged
+}
+
+impl Input {
+    // A single unscaled, unclamped wire can hand over its source's values as they are.
+    fn passthrough(&self) -> bool {
+        self.feeds.len() == 1 && self.feeds[0].gain == 1.0 && self.feeds[0].offset == 0.0 && self.clamp.is_none()
+    }
+}
+
+// Execution plan derived from the wires.
+// Modules run in topological order so that a value settles along a chain of
+// wires in a single sample. Wires that close a feedback cycle read the value
+// their source produced on the previous sample.
+struct Plan {
+    order: Vec<usize>,
+    inputs: Vec<Vec<Input>>, // wired inputs of each module, indexed by module position
+    events: Vec<Vec<EventFeed>>, // wired event inputs of each module
+    feedback: bool, // true if any wire closes a cycle
+    delayed: Vec<usize>, // wires that close a cycle, read a sample late
+
+    // for running in blocks
+    num_inputs: Vec<usize>,
+    bufs: Vec<Vec<Vec<f64>>>, // a block of values for each output of each module
+    out_events: Vec<Vec<Timed>>, // events sent by each module during the block
+    in_events: Vec<Timed>, // events for the module being run
+}
+
+// Largest number of samples moved along the wires at once.
+const BLOCK_SIZE: usize = 128;
+
+// A problem found in a rack that doesn't stop it from running.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub origin: String, // config file and line, or empty if unknown
+    pub msg: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.origin.is_empty() {
+            write!(f, "warning: {}", self.msg)
+        } else {
+            write!(f, "{}: warning: {}", self.origin, self.msg)
+        }
+    }
+}
+
 // a rack owns all of its modules and manages them
 pub struct Rack {
-    modules: HashMap<String, ModRef>,
+    rate: f64, // sampling rate in Hz
+    modules: Vec<(String, ModRef)>, // in the order they were added
     wires: Vec<Wire>,
-    // XXX cache of output values for each module...
+    plan: Option<Plan>, // rebuilt whenever modules or wires change
+
+    origin: String, // where the next modules and wires are defined
+    origins: HashMap<String, String>, // where each module was defined
 }
 
 impl Rack {
     pub fn new() -> Self {
+        Self::new_at(SAMPLE_RATE)
+    }
+
+    pub fn new_at(rate: f64) -> Self {
         Rack {
-            modules: HashMap::new(),
+            rate,
+            modules: Vec::new(),
             wires: Vec::new(),
+            plan: None,
+            origin: String::new(),
+            origins: HashMap::new(),
         }
     }
 
+    pub fn sample_rate(&self) -> f64 {
+        self.rate
+    }
+
+    // Change the sampling rate of the rack and all of its modules.
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        for (_, m) in self.modules.iter() {
+            m.borrow_mut().set_sample_rate(rate);
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.modules.iter().position(|(nm, _)| nm == name)
+    }
+
+    // Names of the modules, in the order they were added.
+    pub fn module_names(&self) -> Vec<&str> {
+        self.modules.iter().map(|(nm, _)| nm.as_str()).collect()
+    }
+
+    pub fn has_module(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    fn get_module(&self, name: &str) -> Result<&ModRef, String> {
+        self.find(name).map(|idx| &self.modules[idx].1).ok_or(format!("no module named {}", name))
+    }
+
+    // Note where the modules and wires added from now on were defined,
+    // such as "file.rau:12", so that diagnostics can point at them.
+    pub fn set_origin(&mut self, origin: &str) {
+        self.origin = origin.to_owned();
+    }
+
+    // A shared reference to a module.
+    pub fn module(&self, name: &str) -> Result<ModRef, String> {
+        self.get_module(name).cloned()
+    }
+
     // Add a module and its associated ID (name).
     pub fn add_module(&mut self, name: &str, m: ModRef) -> Result<(), String> {
-        if self.modules.contains_key(name) {
+        if self.find(name).is_some() {
+            return Err(format!("redefinition of {}", name));
+        }
+        m.borrow_mut().set_sample_rate(self.rate);
+        self.add_running_module(name, m)
+    }
+
+    // Add a module that already runs at the rack's sampling rate, such as one
+    // taken from another rack, without resetting its state.
+    pub fn add_running_module(&mut self, name: &str, m: ModRef) -> Result<(), String> {
+        if self.find(name).is_some() {
             Err(format!("redefinition of {}", name))
         } else {
-            self.modules.insert(name.to_owned(), m);
+            self.modules.push((name.to_owned(), m));
+            self.origins.insert(name.to_owned(), self.origin.clone());
+            self.plan = None;
             Ok(())
         }
     }
 
-    // Add a wire and return its index on success.
+    // Add a wire between a module output and a module input.
     pub fn add_wire(&mut self,
                     from_mod_name: &str, from_out_name: &str,
                     to_mod_name: &str, to_in_name: &str) -> Result<(), String> {
-        let from_mod = self.modules.get(from_mod_name).ok_or(format!("no module {}", from_mod_name))?;
-        let to_mod = self.modules.get(to_mod_name).ok_or(format!("no module {}", to_mod_name))?;
+        self.add_scaled_wire(from_mod_name, from_out_name, to_mod_name, to_in_name, 1.0, 0.0)
+    }
 
-        let out_idx = from_mod.borrow().output_idx(from_mod_name, from_out_name)?;
-        let in_idx = to_mod.borrow().input_idx(to_mod_name, to_in_name)?;
+    // Add a wire that delivers gain * output + offset to the input.
+    // An input with several wires gets the sum of them.
+    pub fn add_scaled_wire(&mut self,
+                    from_mod_name: &str, from_out_name: &str,
+                    to_mod_name: &str, to_in_name: &str,
+                    gain: f64, offset: f64) -> Result<(), String> {
+        let from_mod = self.modules.iter().find(|(nm, _)| nm == from_mod_name).ok_or(format!("no module {}", from_mod_name))?;
+        let to_mod = self.modules.iter().find(|(nm, _)| nm == to_mod_name).ok_or(format!("no module {}", to_mod_name))?;
+
+        let out_idx = from_mod.1.borrow().output_idx(from_mod_name, from_out_name)?;
+        let in_idx = to_mod.1.borrow().input_idx(to_mod_name, to_in_name)?;
+
+        if !gain.is_finite() || !offset.is_finite() {
+            return Err(format!("wire into {}'s {} needs a finite gain and offset", to_mod_name, to_in_name));
+        }
 
-        if self.wires.iter().any(|w| w.to_mod_name == to_mod_name && w.to_in == in_idx) {
-            return Err(format!("{}'s {} input is already connected", to_mod_name, to_in_name));
+        // Values are clamped to the input's range while running,
+        // but a wire that can never produce an acceptable value is an error.
+        let (_, outs) = from_mod.1.borrow().get_terminals();
+        let (ins, _) = to_mod.1.borrow().get_terminals();
+        let (out, inp) = (&outs[out_idx], &ins[in_idx]);
+        if (out.unit == Unit::Event) != (inp.unit == Unit::Event) {
+            return Err(format!("can't wire {}'s {} to {}'s {}, only one of them carries events",
+                from_mod_name, out.name, to_mod_name, inp.name));
+        }
+        if out.unit == Unit::Event && (gain != 1.0 || offset != 0.0) {
+            return Err(format!("events from {}'s {} can't be scaled or offset", from_mod_name, out.name));
+        }
+        let (min, max) = scaled_range(out.min, out.max, gain, offset);
+        if max < inp.min || min > inp.max {
+            return Err(format!("{}'s {}{} ({} to {}) can't reach the range of {}'s {} ({} to {})",
+                from_mod_name, out.name, scale_text(gain, offset), min, max, to_mod_name, inp.name, inp.min, inp.max));
         }
 
         let wire = Wire {
-            from_mod: from_mod.to_owned(),
+            from_mod_name: from_mod_name.to_owned(),
             from_out: out_idx,
             to_mod_name: to_mod_name.to_owned(),
-            to_mod: to_mod.to_owned(),
             to_in: in_idx,
+            gain,
+            offset,
+            origin: self.origin.clone(),
         };
         self.wires.push(wire);
+        self.plan = None;
+        Ok(())
+    }
+
+    // The changes below keep the state of the modules they don't touch and can be
+    // made between calls to advance or run, as a patch plays.
+
+    // Take a module and all of its wires out of the rack.
+    pub fn remove_module(&mut self, name: &str) -> Result<ModRef, String> {
+        let idx = self.find(name).ok_or(format!("no module {}", name))?;
+        let (_, m) = self.modules.remove(idx);
+        self.wires.retain(|w| w.from_mod_name != name && w.to_mod_name != name);
+        self.origins.remove(name);
+        self.plan = None;
+        Ok(m)
+    }
+
+    // Remove the wires from a module output to a module input.
+    pub fn remove_wire(&mut self,
+                       from_mod_name: &str, from_out_name: &str,
+                       to_mod_name: &str, to_in_name: &str) -> Result<(), String> {
+        let out_idx = self.get_module(from_mod_name)?.borrow().output_idx(from_mod_name, from_out_name)?;
+        let in_idx = self.get_module(to_mod_name)?.borrow().input_idx(to_mod_name, to_in_name)?;
+        let count = self.wires.len();
+        self.wires.retain(|w| !(w.from_mod_name == from_mod_name && w.from_out == out_idx && w.to_mod_name == to_mod_name && w.to_in == in_idx));
+        if self.wires.len() == count {
+            return Err(format!("no wire from {}:{} to {}:{}", from_mod_name, from_out_name, to_mod_name, to_in_name));
+        }
+        self.plan = None;
         Ok(())
     }
 
+    // Put a new module in the place of an existing one and return the old one.
+    // Wires are kept if the new module has terminals with the same names
+    // whose range they can reach, and dropped otherwise.
+    pub fn replace_module(&mut self, name: &str, m: ModRef) -> Result<ModRef, String> {
+        let idx = self.find(name).ok_or(format!("no module {}", name))?;
+        m.borrow_mut().set_sample_rate(self.rate);
+        let (old_ins, old_outs) = self.modules[idx].1.borrow().get_terminals();
+        let (new_ins, new_outs) = m.borrow().get_terminals();
+        let old = std::mem::replace(&mut self.modules[idx].1, m);
+
+        let mut wires = std::mem::take(&mut self.wires);
+        for w in wires.iter_mut() {
+            if w.from_mod_name == name {
+                w.from_out = new_outs.iter().position(|t| t.name == old_outs[w.from_out].name).unwrap_or(usize::MAX);
+            }
+            if w.to_mod_name == name {
+                w.to_in = new_ins.iter().position(|t| t.name == old_ins[w.to_in].name).unwrap_or(usize::MAX);
+            }
+        }
+        wires.retain(|w| {
+            if w.from_out == usize::MAX || w.to_in == usize::MAX {
+                return false;
+            }
+            // add_wire guarantees both ends exist
+            let (_, outs) = self.get_module(&w.from_mod_name).unwrap().borrow().get_terminals();
+            let (ins, _) = self.get_module(&w.to_mod_name).unwrap().borrow().get_terminals();
+            let (out, inp) = (&outs[w.from_out], &ins[w.to_in]);
+            let (min, max) = scaled_range(out.min, out.max, w.gain, w.offset);
+            (out.unit == Unit::Event) == (inp.unit == Unit::Event) && max >= inp.min && min <= inp.max
+        });
+        self.wires = wires;
+        self.plan = None;
+        Ok(old)
+    }
+
+    // Give a module a new name, keeping its wires.
+    pub fn rename_module(&mut self, name: &str, new_name: &str) -> Result<(), String> {
+        let idx = self.find(name).ok_or(format!("no module {}", name))?;
+        if self.find(new_name).is_some() {
+            return Err(format!("redefinition of {}", new_name));
+        }
+        self.modules[idx].0 = new_name.to_owned();
+        for w in self.wires.iter_mut() {
+            if w.from_mod_name == name {
+                w.from_mod_name = new_name.to_owned();
+            }
+            if w.to_mod_name == name {
+                w.to_mod_name = new_name.to_owned();
+            }
+        }
+        if let Some(origin) = self.origins.remove(name) {
+            self.origins.insert(new_name.to_owned(), origin);
+        }
+        self.plan = None;
+        Ok(())
+    }
+
+    // Order the modules so that every module runs after the modules feeding it.
+    // Ties are broken by the order modules were added, so the result only depends
+    // on the patch and not on how names hash. When the remaining modules all wait
+    // on a cycle, a module in that cycle is run next and its inputs from the rest
+    // of the cycle are delayed by one sample.
+    fn make_plan(&self) -> Plan {
+        let n = self.modules.len();
+        let terms: Vec<_> = self.modules.iter().map(|(_, m)| m.borrow().get_terminals()).collect();
+        let mut inputs: Vec<Vec<Input>> = (0..n).map(|_| Vec::new()).collect();
+        let mut events: Vec<Vec<EventFeed>> = (0..n).map(|_| Vec::new()).collect();
+        for (wire, w) in self.wires.iter().enumerate() {
+            // add_wire guarantees both ends exist
+            let from = self.find(&w.from_mod_name).expect("wire from unknown module");
+            let to = self.find(&w.to_mod_name).expect("wire to unknown module");
+            if terms[to].0[w.to_in].unit == Unit::Event {
+                events[to].push(EventFeed{ wire, from, from_out: w.from_out, to_in: w.to_in });
+                continue;
+            }
+            let feed = Feed{ wire, from, from_out: w.from_out, gain: w.gain, offset: w.offset };
+            match inputs[to].iter_mut().find(|inp| inp.to_in == w.to_in) {
+                Some(inp) => inp.feeds.push(feed),
+                None => inputs[to].push(Input{ to_in: w.to_in, feeds: vec![feed], clamp: None, buf: Vec::new() }),
+            }
+        }
+        for (to, ins) in inputs.iter_mut().enumerate() {
+            for inp in ins.iter_mut() {
+                let (mut min, mut max) = (0.0, 0.0);
+                for f in inp.feeds.iter() {
+                    let out = &terms[f.from].1[f.from_out];
+                    let (lo, hi) = scaled_range(out.min, out.max, f.gain, f.offset);
+                    min += lo;
+                    max += hi;
+                }
+                let t = &terms[to].0[inp.to_in];
+                if min < t.min || max > t.max {
+                    inp.clamp = Some((t.min, t.max));
+                }
+                if !inp.passthrough() {
+                    inp.buf = vec![0.0; BLOCK_SIZE];
+                }
+            }
+        }
+
+        // modules wired into each module
+        let feeders: Vec<Vec<usize>> = (0..n).map(|idx| {
+            inputs[idx].iter().flat_map(|inp| inp.feeds.iter().map(|f| f.from))
+                .chain(events[idx].iter().map(|f| f.from))
+                .collect()
+        }).collect();
+
+        let mut placed = vec![false; n];
+        let mut order = Vec::with_capacity(n);
+        while order.len() < n {
+            let ready = (0..n).find(|&idx| !placed[idx] && feeders[idx].iter().all(|&from| placed[from]));
+            let next = ready.unwrap_or_else(|| Self::break_cycle(&feeders, &placed));
+            placed[next] = true;
+            order.push(next);
+        }
+
+        let mut pos = vec![0; n];
+        for (p, &idx) in order.iter().enumerate() {
+            pos[idx] = p;
+        }
+        let mut delayed = Vec::new();
+        for (idx, ins) in inputs.iter().enumerate() {
+            for f in ins.iter().flat_map(|inp| inp.feeds.iter()) {
+                if pos[f.from] >= pos[idx] {
+                    delayed.push(f.wire);
+                }
+            }
+        }
+        for (idx, feeds) in events.iter().enumerate() {
+            for f in feeds.iter() {
+                if pos[f.from] >= pos[idx] {
+                    delayed.push(f.wire);
+                }
+            }
+        }
+        delayed.sort_unstable();
+        let feedback = !delayed.is_empty();
+
+        let num_inputs = terms.iter().map(|(ins, _)| ins.len()).collect();
+        let bufs = terms.iter().map(|(_, outs)| vec![vec![0.0; BLOCK_SIZE]; outs.len()]).collect();
+        let out_events = (0..n).map(|_| Vec::new()).collect();
+        Plan{ order, inputs, events, feedback, delayed, num_inputs, bufs, out_events, in_events: Vec::new() }
+    }
+
+    // Module to run next when every remaining module waits on another: the earliest
+    // added module of a cycle, among the remaining modules, that waits on nothing
+    // outside of the cycle. A module that is only fed by a cycle is never picked.
+    fn break_cycle(feeders: &[Vec<usize>], placed: &[bool]) -> usize {
+        let n = feeders.len();
+        let mut succ = vec![Vec::new(); n];
+        for (to, froms) in feeders.iter().enumerate() {
+            for &from in froms.iter() {
+                if !placed[from] && !placed[to] {
+                    succ[from].push(to);
+                }
+            }
+        }
+        let comp = components(&succ);
+        let blocked = |c: usize| (0..n).filter(|&idx| !placed[idx] && comp[idx] == c)
+            .all(|idx| feeders[idx].iter().all(|&from| placed[from] || comp[from] == c));
+        (0..n).find(|&idx| !placed[idx] && blocked(comp[idx])).expect("no cycle to break")
+    }
+
+    fn plan(&mut self) -> &Plan {
+        if self.plan.is_none() {
+            self.plan = Some(self.make_plan());
+        }
+        self.plan.as_ref().unwrap()
+    }
+
     // Returns false if any module requests a shutdown
     pub fn advance(&mut self) -> bool {
-        // advance the clock of all modules
+        self.plan();
+        let plan = self.plan.as_ref().unwrap();
+
         let mut keep_running = true;
-        for (_, module) in self.modules.iter_mut() {
-            let mut m = module.borrow_mut();
-            let ok = m.advance();
+        for &idx in plan.order.iter() {
+            let module = &self.modules[idx].1;
+
+            // Copy data across the wires into this module, then advance its clock.
+            for inp in plan.inputs[idx].iter() {
+                let mut val = 0.0;
+                for f in inp.feeds.iter() {
+                    let out = self.modules[f.from].1.borrow().get_output(f.from_out).unwrap_or(0.0);
+                    val += out * f.gain + f.offset;
+                }
+                if let Some((min, max)) = inp.clamp {
+                    val = val.clamp(min, max);
+                }
+                module.borrow_mut().set_input(inp.to_in, val);
+            }
+            // a source later in the order still holds the events of the previous sample
+            for f in plan.events[idx].iter() {
+                let events = self.modules[f.from].1.borrow().get_events(f.from_out).to_vec();
+                for event in events {
+                    module.borrow_mut().send_event(f.to_in, event);
+                }
+            }
+            let ok = module.borrow_mut().advance();
             keep_running = keep_running && ok;
         }
+        keep_running
+    }
+
+    // Run a block of up to BLOCK_SIZE samples, moving whole buffers along the wires.
+    // Only valid when there is no feedback, since a cycle needs values from the previous sample.
+    fn advance_block(&mut self, len: usize) -> bool {
+        self.plan();
+        let Self{ modules, plan, .. } = self;
+        let Plan{ order, inputs, events, feedback, num_inputs, bufs, out_events, in_events, .. } = plan.as_mut().unwrap();
+        assert!(!*feedback && len <= BLOCK_SIZE);
+
+        let mut keep_running = true;
+        for &idx in order.iter() {
+            for inp in inputs[idx].iter_mut() {
+                if inp.passthrough() {
+                    continue;
+                }
+                let buf = &mut inp.buf[.. len];
+                buf.fill(0.0);
+                for f in inp.feeds.iter() {
+                    for (dst, src) in buf.iter_mut().zip(bufs[f.from][f.from_out].iter()) {
+                        *dst += src * f.gain + f.offset;
+                    }
+                }
+                if let Some((min, max)) = inp.clamp {
+                    for v in buf.iter_mut() {
+                        *v = v.clamp(min, max);
+                    }
+                }
+            }
 
-        // Copy data across wires
-        for w in self.wires.iter() {
-            let out = w.from_mod.borrow().get_output(w.from_out).unwrap_or(0.0);
-            w.to_mod.borrow_mut().set_input(w.to_in, out);
+            // events from all sources, in order of time
+            in_events.clear();
+            for f in events[idx].iter() {
+                in_events.extend(out_events[f.from].iter().filter(|t| t.term == f.from_out)
+                    .map(|t| Timed{ time: t.time, term: f.to_in, event: t.event }));
+            }
+            if events[idx].len() > 1 {
+                in_events.sort_by_key(|t| t.time);
+            }
+            out_events[idx].clear();
+
+            let mut outbufs = std::mem::take(&mut bufs[idx]);
+            {
+                let mut ins: Vec<&[f64]> = vec![&[]; num_inputs[idx]];
+                for inp in inputs[idx].iter() {
+                    ins[inp.to_in] = if inp.passthrough() {
+                        let f = &inp.feeds[0];
+                        &bufs[f.from][f.from_out][.. len]
+                    } else {
+                        &inp.buf[.. len]
+                    };
+                }
+                let mut outputs: Vec<&mut [f64]> = outbufs.iter_mut().map(|b| &mut b[.. len]).collect();
+                let ok = modules[idx].1.borrow_mut().process_block(len, &ins, &mut outputs, in_events, &mut out_events[idx]);
+                keep_running = keep_running && ok;
+            }
+            bufs[idx] = outbufs;
         }
-        return keep_running;
+        keep_running
     }
 
+    // Run for some time, returns false if any module requests a shutdown.
+    // Patches without feedback are run a block at a time.
     pub fn run(&mut self, time: impl Into<Samples>) -> bool {
-        let samples : Samples = time.into();
-        for _ in 0 .. samples.0 {
-            if !self.advance() {
-                return false;
+        let Samples(mut samples) = time.into();
+        if self.plan().feedback {
+            for _ in 0 .. samples {
+                if !self.advance() {
+                    return false;
+                }
+            }
+        } else {
+            while samples > 0 {
+                let len = samples.min(BLOCK_SIZE);
+                if !self.advance_block(len) {
+                    return false;
+                }
+                samples -= len;
             }
         }
         true
     }
 
+    pub fn get_terminals(&self, mod_name: &str) -> Result<(Vec<TerminalDescr>, Vec<TerminalDescr>), String> {
+        Ok(self.get_module(mod_name)?.borrow().get_terminals())
+    }
+
+    // Whether any wire drives an input, given by its index.
+    pub fn input_wired(&self, mod_name: &str, idx: usize) -> bool {
+        self.wires.iter().any(|w| w.to_mod_name == mod_name && w.to_in == idx)
+    }
+
+    // The wires as "module:output module:input" with any gain and offset, in the order they were added.
+    pub fn wire_list(&self) -> Vec<String> {
+        self.wires.iter().map(|w| {
+            // add_wire guarantees both ends exist
+            let (_, from_outs) = self.get_module(&w.from_mod_name).unwrap().borrow().get_terminals();
+            let (to_ins, _) = self.get_module(&w.to_mod_name).unwrap().borrow().get_terminals();
+            format!("{}:{} {}:{}{}", w.from_mod_name, from_outs[w.from_out].name, w.to_mod_name, to_ins[w.to_in].name, scale_text(w.gain, w.offset))
+        }).collect()
+    }
+
+    // Config text that loads back into an equivalent rack.
+    // Inputs that aren't wired keep their current values with "set" lines.
+    pub fn to_rau(&self) -> String {
+        let mut s = String::new();
+        for (name, m) in self.modules.iter() {
+            s.push_str(&format!("{} {}\n", name, m.borrow().get_args().join(" ")));
+        }
+
+        if !self.wires.is_empty() {
+            s.push('\n');
+        }
+        for w in self.wire_list() {
+            s.push_str(&format!("wire {}\n", w));
+        }
+
+        let mut sets = String::new();
+        for (name, m) in self.modules.iter() {
+            let m = m.borrow();
+            let (ins, _) = m.get_terminals();
+            for (idx, t) in ins.iter().enumerate() {
+                match m.get_input(idx) {
+                    Some(v) if !self.input_wired(name, idx) && v != t.default => sets.push_str(&format!("set {}:{} {}\n", name, t.name, v)),
+                    _ => (),
+                }
+            }
+        }
+        if !sets.is_empty() {
+            s.push('\n');
+            s.push_str(&sets);
+        }
+        s
+    }
+
+    // Module positions at both ends of each wire.
+    fn wire_ends(&self) -> Vec<(usize, usize)> {
+        // add_wire guarantees both ends exist
+        self.wires.iter()
+            .map(|w| (self.find(&w.from_mod_name).unwrap(), self.find(&w.to_mod_name).unwrap()))
+            .collect()
+    }
+
+    // reach[a][b] is true if a path of wires leads from module a to module b
+    fn reachability(&self, ends: &[(usize, usize)]) -> Vec<Vec<bool>> {
+        let n = self.modules.len();
+        let mut reach = vec![vec![false; n]; n];
+        for (start, row) in reach.iter_mut().enumerate() {
+            let mut todo = vec![start];
+            while let Some(idx) = todo.pop() {
+                for &(from, to) in ends.iter() {
+                    if from == idx && !row[to] {
+                        row[to] = true;
+                        todo.push(to);
+                    }
+                }
+            }
+        }
+        reach
+    }
+
+    // delayed[w] is true if the plan reads wire w a sample late to break a cycle.
+    fn delayed_wires(&self) -> Vec<bool> {
+        let mut delayed = vec![false; self.wires.len()];
+        for &w in self.make_plan().delayed.iter() {
+            delayed[w] = true;
+        }
+        delayed
+    }
+
+    // Look for likely mistakes in the patch: modules whose output never reaches
+    // a speaker or file, required inputs left unconnected, no output modules at all,
+    // feedback loops that only run because the rack delays them by a sample,
+    // and inputs set outside of their range. Loops through a module with a delay
+    // of its own, such as a delay line, are meant to be delayed and aren't reported.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let ends = self.wire_ends();
+        let reach = self.reachability(&ends);
+        let delayed = self.delayed_wires();
+        let mut diags = Vec::new();
+        let mut warn = |origin: &str, msg: String| diags.push(Diagnostic{ origin: origin.to_owned(), msg });
+
+        // modules without outputs, such as speakers and files, are where a patch ends
+        let sinks: Vec<usize> = (0..self.modules.len())
+            .filter(|&idx| self.modules[idx].1.borrow().get_terminals().1.is_empty())
+            .collect();
+        if sinks.is_empty() {
+            warn("", "no output module such as a speaker or file".to_string());
+        }
+
+        for (idx, (name, m)) in self.modules.iter().enumerate() {
+            let origin = &self.origins[name];
+            let m = m.borrow();
+            let (ins, outs) = m.get_terminals();
+            // a module played with events, like an envelope, doesn't need its levels too
+            let has_events = self.wires.iter().any(|w| &w.to_mod_name == name && ins[w.to_in].unit == Unit::Event);
+            if !sinks.is_empty() && !outs.is_empty() && !sinks.iter().any(|&sink| reach[idx][sink]) {
+                warn(origin, format!("{} is unused, its outputs never reach an output module", name));
+            }
+            for (t, term) in ins.iter().enumerate() {
+                if self.wires.iter().any(|w| &w.to_mod_name == name && w.to_in == t) {
+                    continue;
+                }
+                match m.get_input(t) {
+                    Some(v) if !term.in_range(v) =>
+                        warn(origin, format!("{}'s {} is {}, outside of {} to {}", name, term.name, v, term.min, term.max)),
+                    Some(v) if term.required && v == term.default && !has_events =>
+                        warn(origin, format!("{}'s {} input is not connected", name, term.name)),
+                    None if term.required && !(has_events && term.unit != Unit::Event) =>
+                        warn(origin, format!("{}'s {} input is not connected", name, term.name)),
+                    _ => (),
+                }
+            }
+        }
+
+        for (wire, w) in self.wires.iter().enumerate() {
+            if !delayed[wire] {
+                continue;
+            }
+            let (from, to) = ends[wire];
+            let looped: Vec<usize> = (0..self.modules.len())
+                .filter(|&idx| reach[to][idx] && reach[idx][from] || idx == from || idx == to)
+                .collect();
+            if looped.iter().any(|&idx| self.modules[idx].1.borrow().has_delay()) {
+                continue;
+            }
+            let (_, outs) = self.modules[from].1.borrow().get_terminals();
+            let (ins, _) = self.modules[to].1.borrow().get_terminals();
+            let names: Vec<&str> = looped.iter().map(|&idx| self.modules[idx].0.as_str()).collect();
+            warn(&w.origin, format!("feedback loop through {} has no delay, wire {}:{} {}:{} is delayed by one sample",
+                names.join(", "), w.from_mod_name, outs[w.from_out].name, w.to_mod_name, ins[w.to_in].name));
+        }
+        diags
+    }
+
+    // Graphviz DOT text showing modules with their terminals and the wires between them.
+    // Unconnected inputs and outputs are shaded, and wires in feedback cycles are red,
+    // dashed where the cycle is delayed by a sample.
+    pub fn to_dot(&self) -> String {
+        let ends = self.wire_ends();
+        let reach = self.reachability(&ends);
+        let delayed = self.delayed_wires();
+
+        let mut s = String::from("digraph rack {\n    rankdir=LR;\n    node [shape=plaintext];\n");
+        for (idx, (name, m)) in self.modules.iter().enumerate() {
+            let m = m.borrow();
+            let (ins, outs) = m.get_terminals();
+            let cells = |terms: &[TerminalDescr], prefix: &str, color: &str, wired: &dyn Fn(usize) -> bool| {
+                let rows: Vec<String> = terms.iter().enumerate().map(|(t, term)| {
+                    let bg = if wired(t) { String::new() } else { format!(" bgcolor=\"{}\"", color) };
+                    format!("<tr><td port=\"{}{}\"{}>{}</td></tr>", prefix, t, bg, dot_escape(&term.name))
+                }).collect();
+                if rows.is_empty() {
+                    String::new()
+                } else {
+                    format!("<td><table border=\"0\" cellborder=\"1\" cellspacing=\"0\">{}</table></td>", rows.concat())
+                }
+            };
+            let in_cells = cells(&ins, "i", "lightyellow", &|t| self.wires.iter().any(|w| &w.to_mod_name == name && w.to_in == t));
+            let out_cells = cells(&outs, "o", "lightpink", &|t| self.wires.iter().any(|w| &w.from_mod_name == name && w.from_out == t));
+            s.push_str(&format!("    m{} [label=<<table border=\"0\" cellborder=\"1\" cellspacing=\"0\"><tr>{}<td><b>{}</b><br/>{}</td>{}</tr></table>>];\n",
+                idx, in_cells, dot_escape(name), dot_escape(&m.get_args().join(" ")), out_cells));
+        }
+
+        for ((w, &(from, to)), &delayed) in self.wires.iter().zip(ends.iter()).zip(delayed.iter()) {
+            let (_, outs) = self.modules[from].1.borrow().get_terminals();
+            let (ins, _) = self.modules[to].1.borrow().get_terminals();
+            let mut attrs = format!("label=\"{} -> {}{}", outs[w.from_out].name.replace('"', "\\\""), ins[w.to_in].name.replace('"', "\\\""), scale_text(w.gain, w.offset));
+            if reach[to][from] {
+                if delayed {
+                    attrs.push_str(" (delayed)\", color=red, style=dashed");
+                } else {
+                    attrs.push_str("\", color=red");
+                }
+            } else {
+                attrs.push('"');
+            }
+            s.push_str(&format!("    m{}:o{} -> m{}:i{} [{}];\n", from, w.from_out, to, w.to_in, attrs));
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    pub fn save(&self, fname: &str) -> Result<(), String> {
+        fs::write(fname, self.to_rau()).map_err(|e| format!("{}: {}", fname, e))
+    }
+
     pub fn set_input(&mut self, mod_name: &str, in_name: &str, val: f64) -> Result<(), String> {
-        let m = self.modules.get(mod_name).ok_or(format!("no module named {}", mod_name))?;
+        let m = self.get_module(mod_name)?;
         m.borrow_mut().set_named_input(mod_name, in_name, val)
     }
     pub fn get_output(&mut self, mod_name: &str, out_name: &str) -> Result<f64, String> {
-        let m = self.modules.get(mod_name).ok_or(format!("no module named {}", mod_name))?;
+        let m = self.get_module(mod_name)?;
         m.borrow().get_named_output(mod_name, out_name)
     }
+    // Events sent from an event output on the last sample.
+    pub fn get_events(&self, mod_name: &str, out_name: &str) -> Result<Vec<Event>, String> {
+        let m = self.get_module(mod_name)?.borrow();
+        let idx = m.output_idx(mod_name, out_name)?;
+        Ok(m.get_events(idx).to_vec())
+    }
+    pub fn get_input(&self, mod_name: &str, in_name: &str) -> Result<f64, String> {
+        let m = self.get_module(mod_name)?.borrow();
+        let idx = m.input_idx(mod_name, in_name)?;
+        m.get_input(idx).ok_or(format!("can't read {}'s {}", mod_name, in_name))
+    }
+}
+
+// Strongly connected components of a graph given by the successors of each
+// node, numbered so that nodes share a number when each can reach the other.
+// reference: Kosaraju's algorithm, https://en.wikipedia.org/wiki/Kosaraju%27s_algorithm
+fn components(succ: &[Vec<usize>]) -> Vec<usize> {
+    let n = succ.len();
+    let mut pred = vec![Vec::new(); n];
+    for (from, tos) in succ.iter().enumerate() {
+        for &to in tos.iter() {
+            pred[to].push(from);
+        }
+    }
+
+    // nodes in the order a depth first search finishes with them
+    let mut seen = vec![false; n];
+    let mut finished = Vec::with_capacity(n);
+    for start in 0..n {
+        if seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        let mut stack = vec![(start, 0)];
+        while let Some((node, i)) = stack.pop() {
+            if i < succ[node].len() {
+                stack.push((node, i + 1));
+                let next = succ[node][i];
+                if !seen[next] {
+                    seen[next] = true;
+                    stack.push((next, 0));
+                }
+            } else {
+                finished.push(node);
+            }
+        }
+    }
+
+    // searching backwards from the last to finish finds one component at a time
+    let mut comp = vec![usize::MAX; n];
+    let mut count = 0;
+    for &start in finished.iter().rev() {
+        if comp[start] != usize::MAX {
+            continue;
+        }
+        comp[start] = count;
+        let mut todo = vec![start];
+        while let Some(node) = todo.pop() {
+            for &prev in pred[node].iter() {
+                if comp[prev] == usize::MAX {
+                    comp[prev] = count;
+                    todo.push(prev);
+                }
+            }
+        }
+        count += 1;
+    }
+    comp
+}
+
+// Escape text for graphviz labels.
+fn dot_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
 }
 
 pub fn parse<T: FromStr>(name: &str, val: &str) -> Result<T, String> {
@@ -182,3 +1101,181 @@ pub fn mod_inc(n: usize, max: usize) -> usize {
 pub fn mod_sub(n: usize, m: usize, max: usize) -> usize {
     if m <= n { n - m } else { n + max - m }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delay::Delay;
+    use crate::units::Sec;
+    use crate::util::{Bias, Const, Mix};
+
+    fn positions(rack: &mut Rack) -> HashMap<String, usize> {
+        let names: Vec<String> = rack.module_names().iter().map(|s| s.to_string()).collect();
+        let order = rack.plan().order.clone();
+        order.iter().enumerate().map(|(p, &idx)| (names[idx].clone(), p)).collect()
+    }
+
+    #[test]
+    fn module_fed_by_cycle_is_not_delayed() {
+        let mut rack = Rack::new();
+        rack.add_module("out", modref_new(Mix::new())).unwrap();
+        rack.add_module("a", modref_new(Mix::new())).unwrap();
+        rack.add_module("b", modref_new(Bias::new(0.0, 0.5))).unwrap();
+        rack.add_module("src", modref_new(Const::new(1.0))).unwrap();
+        rack.add_wire("src", "out", "a", "in").unwrap();
+        rack.add_wire("a", "out", "b", "in").unwrap();
+        rack.add_wire("b", "out", "a", "in").unwrap();
+        rack.add_wire("b", "out", "out", "in").unwrap();
+
+        let pos = positions(&mut rack);
+        assert!(pos["src"] < pos["a"] && pos["a"] < pos["b"] && pos["b"] < pos["out"]);
+
+        // out follows b in the same sample
+        rack.advance();
+        assert_eq!(rack.get_output("b", "out").unwrap(), 0.5);
+        assert_eq!(rack.get_output("out", "out").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn cycle_is_broken_inside_the_remaining_cycle() {
+        // a -> c <-> d -> e -> a, with e only fed by the inner cycle once a has run
+        let mut rack = Rack::new();
+        rack.add_module("a", modref_new(Mix::new())).unwrap();
+        rack.add_module("e", modref_new(Mix::new())).unwrap();
+        rack.add_module("c", modref_new(Mix::new())).unwrap();
+        rack.add_module("d", modref_new(Bias::new(0.0, 0.5))).unwrap();
+        rack.add_wire("a", "out", "c", "in").unwrap();
+        rack.add_wire("c", "out", "d", "in").unwrap();
+        rack.add_wire("d", "out", "c", "in").unwrap();
+        rack.add_wire("d", "out", "e", "in").unwrap();
+        rack.add_wire("e", "out", "a", "in").unwrap();
+
+        let pos = positions(&mut rack);
+        assert_eq!(pos["a"], 0);
+        assert!(pos["c"] < pos["d"] && pos["d"] < pos["e"]);
+    }
+
+    fn feedback_warnings(rack: &Rack) -> Vec<String> {
+        rack.validate().into_iter().map(|d| d.msg).filter(|msg| msg.starts_with("feedback")).collect()
+    }
+
+    #[test]
+    fn validate_reports_the_delayed_wire() {
+        let mut rack = Rack::new();
+        rack.add_module("a", modref_new(Mix::new())).unwrap();
+        rack.add_module("b", modref_new(Bias::new(0.0, 0.5))).unwrap();
+        rack.add_wire("a", "out", "b", "in").unwrap();
+        rack.add_wire("b", "out", "a", "in").unwrap();
+        let warnings = feedback_warnings(&rack);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].ends_with("wire b:out a:in is delayed by one sample"), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn validate_accepts_loops_through_a_delay() {
+        let mut rack = Rack::new();
+        rack.add_module("a", modref_new(Mix::new())).unwrap();
+        rack.add_module("d", modref_new(Delay::new(Sec(0.01), 0.0, 0.0))).unwrap();
+        rack.add_wire("a", "out", "d", "in").unwrap();
+        rack.add_wire("d", "out", "a", "in").unwrap();
+        assert!(feedback_warnings(&rack).is_empty());
+    }
+
+    #[test]
+    fn chain_without_cycles_runs_in_one_sample() {
+        let mut rack = Rack::new();
+        rack.add_module("out", modref_new(Bias::new(1.0, 2.0))).unwrap();
+        rack.add_module("mid", modref_new(Bias::new(0.0, 3.0))).unwrap();
+        rack.add_module("src", modref_new(Const::new(1.0))).unwrap();
+        rack.add_wire("src", "out", "mid", "in").unwrap();
+        rack.add_wire("mid", "out", "out", "in").unwrap();
+        assert!(!rack.plan().feedback);
+        rack.advance();
+        assert_eq!(rack.get_output("out", "out").unwrap(), 7.0);
+    }
+
+    // Records the values and events it receives on each sample.
+    struct Probe {
+        log: Rc<RefCell<Vec<(usize, f64, Vec<Event>)>>>,
+        sample: usize,
+        val: f64,
+        events: Vec<Event>,
+    }
+
+    impl Module for Probe {
+        fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+            (vec![TerminalDescr::new("in", Unit::Value), TerminalDescr::new("notes", Unit::Event)], vec![])
+        }
+        fn get_output(&self, _idx: usize) -> Option<f64> {
+            None
+        }
+        fn set_input(&mut self, _idx: usize, value: f64) {
+            self.val = value;
+        }
+        fn send_event(&mut self, _idx: usize, event: Event) {
+            self.events.push(event);
+        }
+        fn advance(&mut self) -> bool {
+            self.log.borrow_mut().push((self.sample, self.val, std::mem::take(&mut self.events)));
+            self.sample += 1;
+            true
+        }
+        fn get_args(&self) -> Vec<String> {
+            vec!["probe".to_string()]
+        }
+    }
+
+    const BLOCK_PATCH: &str = "lfo osc2 sin 3 amp=0.5 off=0.5
+        o osc2 sawup 220 mode=blep
+        a osc sawup 110 16
+        clk osc2 square 30 amp=0.5 off=0.5
+        wire clk:out a:sync
+        song midifile configs/midi/scale.mid loop 1
+        env envelope 5ms 50ms 0.5 20ms
+        wire song:notes2 env:notes
+        f filter lp 1kHz
+        wire o:out f:in
+        wire lfo:out f:freq *2000 +300
+        m mult
+        wire f:out m:in1
+        wire env:out m:in2
+        x mix
+        wire m:out x:in
+        wire a:subosc1 x:in *0.3";
+
+    fn probed_rack(log: &Rc<RefCell<Vec<(usize, f64, Vec<Event>)>>>) -> Rack {
+        let mut l = Loader::new();
+        let mut rack = Rack::new();
+        for line in BLOCK_PATCH.lines() {
+            l.exec_line(&mut rack, line.trim()).unwrap();
+        }
+        rack.add_module("probe", modref_new(Probe{ log: log.clone(), sample: 0, val: 0.0, events: Vec::new() })).unwrap();
+        rack.add_wire("x", "out", "probe", "in").unwrap();
+        rack.add_wire("song", "notes2", "probe", "notes").unwrap();
+        rack
+    }
+
+    #[test]
+    fn blocks_run_like_single_samples() {
+        let (log1, log2) = (Rc::new(RefCell::new(Vec::new())), Rc::new(RefCell::new(Vec::new())));
+        let mut rack1 = probed_rack(&log1);
+        let mut rack2 = probed_rack(&log2);
+        assert!(!rack1.plan().feedback);
+
+        let n = 20000; // not a whole number of blocks
+        rack1.run(Samples(n));
+        for _ in 0 .. n {
+            rack2.advance();
+        }
+        let (log1, log2) = (log1.borrow(), log2.borrow());
+        assert_eq!(log1.len(), n);
+        assert!(log1.iter().any(|(_, _, evs)| !evs.is_empty()));
+        assert!(log1.iter().any(|(_, v, _)| *v != 0.0));
+        for (a, b) in log1.iter().zip(log2.iter()) {
+            assert_eq!(a, b);
+        }
+        for name in ["o", "a", "f", "env", "m", "x"] {
+            assert_eq!(rack1.get_output(name, "out").unwrap(), rack2.get_output(name, "out").unwrap(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+
+/*
+ * Noise sources and sample and hold, driven by a small seeded random number
+ * generator so that renders come out the same every time.
+ */
+
+use std::f64::consts::PI;
+use std::str::FromStr;
+use crate::units::{Hz, SAMPLE_RATE, MAXHZ};
+use crate::module::*;
+
+const PINK_ROWS: usize = 16;
+const BROWN_GAIN: f64 = 3.5; // the walk rarely strays far from 0
+
+// xorshift64* random numbers, seeded through splitmix64 so that nearby seeds
+// give unrelated sequences.
+// reference: Vigna, "An experimental exploration of Marsaglia's xorshift generators, scrambled"
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        Rng(if z == 0 { 1 } else { z })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    // uniform in -1 .. 1
+    pub fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Color {
+    White, // flat spectrum
+    Pink, // -3dB per octave, equal power in each octave
+    Brown, // -6dB per octave, a random walk
+    Blue, // +3dB per octave
+}
+
+pub const COLORS: &[&str] = &["white", "pink", "brown", "blue"];
+
+impl Color {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Color::White => "white",
+            Color::Pink => "pink",
+            Color::Brown => "brown",
+            Color::Blue => "blue",
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "white" { return Ok(Color::White); }
+        if s == "pink" { return Ok(Color::Pink); }
+        if s == "brown" { return Ok(Color::Brown); }
+        if s == "blue" { return Ok(Color::Blue); }
+        Err(format!("unrecognized color '{}'", s))
+    }
+}
+
+pub struct Noise {
+    color: Color,
+    seed: u64,
+    rng: Rng,
+    rows: [f64; PINK_ROWS], // pink noise rows, row k changes every 2^(k+1) samples
+    count: u32,
+    last: f64, // previous pink sample for blue, or the walk for brown
+    val: f64,
+}
+
+impl Noise {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("noise", "source", "white, pink, brown or blue noise")
+            .arg(ArgDescr::new("color", ArgType::choice(COLORS)).default("white").descr("spectrum of the noise"))
+            .arg(ArgDescr::new("seed", ArgType::Int).range(0.0, f64::INFINITY).default("1").descr("random number seed, the same seed gives the same noise"))
+            .output(TerminalDescr::new("out", Unit::Audio).range(-1.0, 1.0))
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 3 {
+            return Err(format!("usage: {} color seed", args[0]));
+        }
+        let color = parse::<Color>("color", args[1])?;
+        let seed = parse::<u64>("seed", args[2])?;
+        Ok( modref_new(Self::new(color, seed)) )
+    }
+
+    pub fn new(color: Color, seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut rows = [0.0; PINK_ROWS];
+        for row in rows.iter_mut() {
+            *row = rng.uniform();
+        }
+        Noise { color, seed, rng, rows, count: 0, last: 0.0, val: 0.0 }
+    }
+
+    // Voss-McCartney: sum rows of random values that are each updated half as
+    // often as the row before, picking the row by the trailing zeros of a counter.
+    // reference: https://www.firstpr.com.au/dsp/pink-noise/
+    fn pink(&mut self) -> f64 {
+        self.count = self.count.wrapping_add(1);
+        let row = self.count.trailing_zeros() as usize;
+        if row < PINK_ROWS {
+            self.rows[row] = self.rng.uniform();
+        }
+        let sum: f64 = self.rows.iter().sum::<f64>() + self.rng.uniform();
+        sum / (PINK_ROWS + 1) as f64 // each term is within -1 .. 1
+    }
+
+    // Next sample, before clamping.
+    fn next(&mut self) -> f64 {
+        match self.color {
+            Color::White => self.rng.uniform(),
+            Color::Pink => self.pink(),
+            Color::Brown => {
+                // leak a little so the walk doesn't wander off,
+                // and turn a step that would leave -1 .. 1 around
+                let step = 0.02 * self.rng.uniform();
+                let walk = (self.last + step) / 1.02;
+                self.last = if walk.abs() * BROWN_GAIN > 1.0 { (self.last - step) / 1.02 } else { walk };
+                self.last * BROWN_GAIN
+            },
+            Color::Blue => {
+                // differentiating pink noise tilts it up by 6dB per octave
+                let p = self.pink();
+                let v = 3.0 * (p - self.last);
+                self.last = p;
+                v
+            },
+        }
+    }
+
+    pub fn advance(&mut self) -> f64 {
+        self.val = self.next().clamp(-1.0, 1.0);
+        self.val
+    }
+}
+
+impl Module for Noise {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        Noise::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["noise".to_string(), self.color.name().to_string(), self.seed.to_string()]
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.val); }
+        None
+    }
+
+    fn set_input(&mut self, _idx: usize, _value: f64) {
+    }
+
+    fn advance(&mut self) -> bool {
+        Noise::advance(self);
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShMode {
+    Sample, // hold the input
+    Random, // hold a random value
+    Smooth, // glide to a random value
+}
+
+pub const SHMODES: &[&str] = &["sample", "random", "smooth"];
+
+impl ShMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShMode::Sample => "sample",
+            ShMode::Random => "random",
+            ShMode::Smooth => "smooth",
+        }
+    }
+}
+
+impl FromStr for ShMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "sample" { return Ok(ShMode::Sample); }
+        if s == "random" { return Ok(ShMode::Random); }
+        if s == "smooth" { return Ok(ShMode::Smooth); }
+        Err(format!("unrecognized sample and hold mode '{}'", s))
+    }
+}
+
+// Sample and hold, taking a new value on each rising edge of trig and on
+// each tick of its own clock.
+pub struct Sh {
+    mode: ShMode,
+    seed: u64,
+    rng: Rng,
+    inp: f64,
+    trig: bool,
+    fired: bool, // trig went high since the last sample
+    freq: Hz,
+    rate: f64,
+
+    clock: f64, // fraction of the clock's period
+    since: usize, // samples since the last new value
+    period: usize, // samples between the last two new values, for gliding
+    from: f64, // value being glided away from
+    to: f64, // value being held or glided to
+    val: f64,
+}
+
+impl Sh {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("sh", "modulation", "sample and hold, or random steps and smooth random drift")
+            .arg(ArgDescr::new("mode", ArgType::choice(SHMODES)).default("sample").descr("hold the input, hold random values, or glide between random values"))
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).default("0.0").descr("rate of its own clock, 0 for none"))
+            .arg(ArgDescr::new("seed", ArgType::Int).range(0.0, f64::INFINITY).default("1").descr("random number seed"))
+            .input(TerminalDescr::new("in", Unit::Value).default(0.0).descr("value to sample"))
+            .input(TerminalDescr::new("trig", Unit::Gate).default(0.0).descr("take a new value when it goes high"))
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).default(0.0).descr("rate of its own clock, 0 for none"))
+            .output(TerminalDescr::new("out", Unit::Value))
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 4 {
+            return Err(format!("usage: {} mode freq seed", args[0]));
+        }
+        let mode = parse::<ShMode>("mode", args[1])?;
+        let freq = parse::<f64>("freq", args[2])?;
+        let seed = parse::<u64>("seed", args[3])?;
+        Ok( modref_new(Self::new(mode, Hz(freq), seed)) )
+    }
+
+    pub fn new(mode: ShMode, freq: Hz, seed: u64) -> Self {
+        Sh {
+            mode,
+            seed,
+            rng: Rng::new(seed),
+            inp: 0.0,
+            trig: false,
+            fired: false,
+            freq,
+            rate: SAMPLE_RATE,
+            clock: 0.0,
+            since: 0,
+            period: 0,
+            from: 0.0,
+            to: 0.0,
+            val: 0.0,
+        }
+    }
+
+    fn take(&mut self) {
+        self.from = self.val;
+        self.to = match self.mode {
+            ShMode::Sample => self.inp,
+            _ => self.rng.uniform(),
+        };
+        // glide over the clock's period, or the time between the last two triggers
+        self.period = if self.freq.0 > 0.0 { (self.rate / self.freq.0) as usize } else { self.since };
+        self.since = 0;
+    }
+
+    pub fn advance(&mut self) -> f64 {
+        self.since += 1;
+        self.clock += self.freq.0 / self.rate;
+        if self.clock >= 1.0 || self.fired {
+            self.clock -= self.clock.floor();
+            self.fired = false;
+            self.take();
+        }
+
+        self.val = if self.mode == ShMode::Smooth && self.since < self.period {
+            let t = self.since as f64 / self.period as f64;
+            self.from + (self.to - self.from) * 0.5 * (1.0 - (PI * t).cos())
+        } else {
+            self.to
+        };
+        self.val
+    }
+}
+
+impl Module for Sh {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        let (mut ins, outs) = Sh::descr().terminals();
+        ins[2].max = self.rate / 2.0;
+        ins[2].default = self.freq.0;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["sh".to_string(), self.mode.name().to_string(), self.freq.0.to_string(), self.seed.to_string()]
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.val); }
+        None
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        match idx {
+            0 => Some(self.inp),
+            1 => Some(if self.trig { 1.0 } else { 0.0 }),
+            2 => Some(self.freq.0),
+            _ => None,
+        }
+    }
+
+    fn set_input(&mut self, idx: usize, value: f64) {
+        match idx {
+            0 => self.inp = value,
+            1 => {
+                let trig = value >= 0.5;
+                self.fired |= trig && !self.trig;
+                self.trig = trig;
+            },
+            2 => self.freq = Hz(value),
+            _ => (),
+        }
+    }
+
+    fn advance(&mut self) -> bool {
+        Sh::advance(self);
+        true
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
+    }
+}
+
+pub fn init(l: &mut Loader) {
+    l.register(Noise::descr(), Noise::from_cmd);
+    l.register(Sh::descr(), Sh::from_cmd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOR_LIST: [Color; 4] = [Color::White, Color::Pink, Color::Brown, Color::Blue];
+
+    fn samples(color: Color, seed: u64, len: usize) -> Vec<f64> {
+        let mut n = Noise::new(color, seed);
+        (0 .. len).map(|_| n.advance()).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_noise() {
+        for color in COLOR_LIST {
+            assert_eq!(samples(color, 7, 1000), samples(color, 7, 1000), "{:?}", color);
+        }
+        let mut a = Sh::new(ShMode::Random, Hz(1000.0), 7);
+        let mut b = Sh::new(ShMode::Random, Hz(1000.0), 7);
+        assert!((0 .. 1000).all(|_| a.advance() == b.advance()));
+    }
+
+    #[test]
+    fn different_seeds_give_different_noise() {
+        for color in COLOR_LIST {
+            let (a, b) = (samples(color, 1, 1000), samples(color, 2, 1000));
+            assert!(a.iter().zip(b.iter()).filter(|(x, y)| x == y).count() < 10, "{:?}", color);
+        }
+    }
+
+    #[test]
+    fn pink_and_brown_stay_in_range_unclamped() {
+        for color in [Color::Pink, Color::Brown] {
+            for seed in 0 .. 4 {
+                let mut n = Noise::new(color, seed);
+                // start at the edge, where it's easiest to step out
+                n.rows = [1.0; PINK_ROWS];
+                n.last = 1.0 / BROWN_GAIN;
+                for i in 0 .. 200_000 {
+                    let v = n.next();
+                    assert!((-1.0 ..= 1.0).contains(&v), "{:?} seed {} sample {}: {}", color, seed, i, v);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sample_mode_only_takes_rising_edges() {
+        let mut sh = Sh::new(ShMode::Sample, Hz(0.0), 1);
+        let trig = [0.0, 1.0, 1.0, 0.0, 0.0, 0.8, 1.0, 0.2, 0.6, 0.7];
+        let mut outs = Vec::new();
+        for (n, t) in trig.iter().enumerate() {
+            sh.set_input(0, n as f64);
+            sh.set_input(1, *t);
+            outs.push(sh.advance());
+        }
+        assert_eq!(outs, vec![0.0, 1.0, 1.0, 1.0, 1.0, 5.0, 5.0, 5.0, 8.0, 8.0]);
+    }
+}
@@ -0,0 +1,316 @@
+
+/*
+ * Control a rack over the network with OSC (Open Sound Control) messages on UDP.
+ * "/rack/filt/freq 800.0" sets filt's freq input, and "/rack/env/out" with no
+ * arguments is answered with a "/rack/env/out value" message, the value being a
+ * double. Problems are answered with a "/rack/error message" message.
+ *
+ * Packets are received on their own thread and handed to the thread running
+ * the rack, which handles them between blocks of samples.
+ * reference: https://opensoundcontrol.stanford.edu/spec-1_0.html
+ */
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+use crate::module::*;
+
+const PREFIX: &str = "/rack/";
+const ERROR_ADDR: &str = "/rack/error";
+const MAX_NESTING: usize = 8; // bundles in bundles
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Double(f64),
+    Str(String),
+}
+
+impl Arg {
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Arg::Int(v) => Some(v as f64),
+            Arg::Float(v) => Some(v as f64),
+            Arg::Double(v) => Some(v),
+            Arg::Str(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub addr: String,
+    pub args: Vec<Arg>,
+}
+
+// Strings are null terminated and padded to a multiple of four bytes.
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    buf.resize(buf.len().div_ceil(4) * 4, 0);
+}
+
+// Reads the parts of a packet in order.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err(format!("osc packet is truncated"));
+        }
+        let bytes = &self.buf[self.pos .. self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<[u8; 4], String> {
+        let mut w = [0; 4];
+        w.copy_from_slice(self.take(4)?);
+        Ok(w)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.buf[self.pos ..];
+        let len = rest.iter().position(|&b| b == 0).ok_or(format!("osc string isn't terminated"))?;
+        let s = String::from_utf8(rest[.. len].to_vec()).map_err(|_| format!("osc string isn't utf8"))?;
+        self.take((len + 4) / 4 * 4)?;
+        Ok(s)
+    }
+}
+
+impl Message {
+    pub fn new(addr: &str, args: Vec<Arg>) -> Self {
+        Message{ addr: addr.to_owned(), args }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        push_str(&mut buf, &self.addr);
+        let tags: String = self.args.iter().map(|a| match a {
+            Arg::Int(_) => 'i',
+            Arg::Float(_) => 'f',
+            Arg::Double(_) => 'd',
+            Arg::Str(_) => 's',
+        }).collect();
+        push_str(&mut buf, &format!(",{}", tags));
+        for a in self.args.iter() {
+            match a {
+                Arg::Int(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                Arg::Float(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                Arg::Double(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                Arg::Str(s) => push_str(&mut buf, s),
+            }
+        }
+        buf
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, String> {
+        let addr = r.string()?;
+        let mut args = Vec::new();
+        if r.pos == r.buf.len() {
+            return Ok(Message{ addr, args }); // old senders may leave out the type tags
+        }
+        let tags = r.string()?;
+        let tags = tags.strip_prefix(',').ok_or(format!("osc message to {} has no type tags", addr))?;
+        for tag in tags.chars() {
+            let arg = match tag {
+                'i' => Arg::Int(i32::from_be_bytes(r.word()?)),
+                'f' => Arg::Float(f32::from_be_bytes(r.word()?)),
+                'd' => {
+                    let mut w = [0; 8];
+                    w.copy_from_slice(r.take(8)?);
+                    Arg::Double(f64::from_be_bytes(w))
+                },
+                's' => Arg::Str(r.string()?),
+                _ => return Err(format!("osc type '{}' isn't supported", tag)),
+            };
+            args.push(arg);
+        }
+        Ok(Message{ addr, args })
+    }
+}
+
+// A bundle of messages to be handled together, as soon as it arrives.
+pub fn encode_bundle(msgs: &[Message]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_str(&mut buf, "#bundle");
+    buf.extend_from_slice(&1u64.to_be_bytes()); // time tag for "immediately"
+    for m in msgs.iter() {
+        let enc = m.encode();
+        buf.extend_from_slice(&(enc.len() as i32).to_be_bytes());
+        buf.extend_from_slice(&enc);
+    }
+    buf
+}
+
+// The messages in a packet, which is a single message or a bundle of them.
+pub fn decode(buf: &[u8]) -> Result<Vec<Message>, String> {
+    decode_nested(buf, 0)
+}
+
+fn decode_nested(buf: &[u8], depth: usize) -> Result<Vec<Message>, String> {
+    let mut r = Reader{ buf, pos: 0 };
+    if !buf.starts_with(b"#bundle\0") {
+        return Ok(vec![Message::read(&mut r)?]);
+    }
+    if depth == MAX_NESTING {
+        return Err(format!("osc bundles are nested more than {} deep", MAX_NESTING));
+    }
+    r.take(16)?; // "#bundle" and a time tag, messages are handled as they arrive
+    let mut msgs = Vec::new();
+    while r.pos < buf.len() {
+        let len = i32::from_be_bytes(r.word()?);
+        if len < 0 {
+            return Err(format!("osc bundle element has a negative size"));
+        }
+        msgs.extend(decode_nested(r.take(len as usize)?, depth + 1)?);
+    }
+    Ok(msgs)
+}
+
+// Module and terminal named by an address such as "/rack/filt/freq".
+fn parse_addr(addr: &str) -> Result<(&str, &str), String> {
+    addr.strip_prefix(PREFIX)
+        .and_then(|rest| rest.rsplit_once('/'))
+        .ok_or(format!("expected an address like {}module/terminal, not {}", PREFIX, addr))
+}
+
+// Set an input, or read an output (or input) when there are no arguments.
+fn handle(rack: &mut Rack, msg: &Message) -> Result<Option<Message>, String> {
+    let (mod_name, term) = parse_addr(&msg.addr)?;
+    match msg.args.as_slice() {
+        [] => {
+            let v = rack.get_output(mod_name, term).or_else(|e| rack.get_input(mod_name, term).map_err(|_| e))?;
+            Ok(Some(Message::new(&msg.addr, vec![Arg::Double(v)])))
+        },
+        [arg] => {
+            let v = arg.as_f64().ok_or(format!("{} needs a number", msg.addr))?;
+            let (ins, _) = rack.get_terminals(mod_name)?;
+            let t = ins.iter().find(|t| t.name == term).ok_or(format!("{} has no input named {}", mod_name, term))?;
+            t.check(mod_name, v)?;
+            rack.set_input(mod_name, term, v)?;
+            Ok(None)
+        },
+        _ => Err(format!("{} takes at most one argument", msg.addr)),
+    }
+}
+
+fn send(socket: &UdpSocket, to: SocketAddr, msg: &Message) {
+    // the sender may be gone, nothing to do about it
+    let _ = socket.send_to(&msg.encode(), to);
+}
+
+fn error_msg(e: &str) -> Message {
+    Message::new(ERROR_ADDR, vec![Arg::Str(e.to_owned())])
+}
+
+pub struct Server {
+    socket: UdpSocket,
+    msgs: Receiver<(Message, SocketAddr)>,
+}
+
+impl Server {
+    // Listen on an address such as "127.0.0.1:9000".
+    pub fn bind(addr: &str) -> Result<Self, String> {
+        let socket = UdpSocket::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+        let recv_socket = socket.try_clone().map_err(|e| format!("{}: {}", addr, e))?;
+        let (tx, msgs) = channel();
+        thread::spawn(move || {
+            let mut buf = vec![0; 65536];
+            loop {
+                let (len, from) = match recv_socket.recv_from(&mut buf) {
+                    Ok(res) => res,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => continue,
+                    Err(e) => {
+                        eprintln!("osc server stopped: {}", e);
+                        return;
+                    },
+                };
+                match decode(&buf[.. len]) {
+                    Ok(ms) => {
+                        for m in ms {
+                            if tx.send((m, from)).is_err() {
+                                return; // the server is gone
+                            }
+                        }
+                    },
+                    Err(e) => send(&recv_socket, from, &error_msg(&e)),
+                }
+            }
+        });
+        Ok(Server{ socket, msgs })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, String> {
+        self.socket.local_addr().map_err(|e| format!("{}", e))
+    }
+
+    // Handle the messages that arrived since the last poll, without waiting for more.
+    pub fn poll(&self, rack: &mut Rack) {
+        while let Ok((msg, from)) = self.msgs.try_recv() {
+            match handle(rack, &msg) {
+                Ok(Some(reply)) => send(&self.socket, from, &reply),
+                Ok(None) => (),
+                Err(e) => send(&self.socket, from, &error_msg(&e)),
+            }
+        }
+    }
+}
+
+// Sends messages to a server and waits for the answers to queries.
+pub struct Client {
+    socket: UdpSocket,
+    server: SocketAddr,
+}
+
+impl Client {
+    pub fn connect(server: SocketAddr) -> Result<Self, String> {
+        let local = if server.ip().is_loopback() { "127.0.0.1:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(local).map_err(|e| format!("{}: {}", local, e))?;
+        socket.set_read_timeout(Some(Duration::from_secs(1))).map_err(|e| format!("{}", e))?;
+        Ok(Client{ socket, server })
+    }
+
+    pub fn send(&self, msg: &Message) -> Result<(), String> {
+        self.socket.send_to(&msg.encode(), self.server).map_err(|e| format!("{}: {}", self.server, e))?;
+        Ok(())
+    }
+
+    pub fn send_bundle(&self, msgs: &[Message]) -> Result<(), String> {
+        self.socket.send_to(&encode_bundle(msgs), self.server).map_err(|e| format!("{}: {}", self.server, e))?;
+        Ok(())
+    }
+
+    pub fn recv(&self) -> Result<Message, String> {
+        let mut buf = vec![0; 65536];
+        let len = self.socket.recv(&mut buf).map_err(|e| format!("{}: {}", self.server, e))?;
+        let msg = decode(&buf[.. len])?.into_iter().next().ok_or(format!("empty osc bundle"))?;
+        if msg.addr == ERROR_ADDR {
+            return Err(match msg.args.first() {
+                Some(Arg::Str(e)) => e.clone(),
+                _ => format!("unknown error"),
+            });
+        }
+        Ok(msg)
+    }
+
+    pub fn set(&self, mod_name: &str, in_name: &str, val: f64) -> Result<(), String> {
+        self.send(&Message::new(&format!("{}{}/{}", PREFIX, mod_name, in_name), vec![Arg::Double(val)]))
+    }
+
+    pub fn query(&self, mod_name: &str, name: &str) -> Result<f64, String> {
+        let addr = format!("{}{}/{}", PREFIX, mod_name, name);
+        self.send(&Message::new(&addr, Vec::new()))?;
+        let msg = self.recv()?;
+        match msg.args.first().and_then(|a| a.as_f64()) {
+            Some(v) if msg.addr == addr => Ok(v),
+            _ => Err(format!("unexpected reply to {}: {:?}", addr, msg)),
+        }
+    }
+}
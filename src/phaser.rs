@@ -1,7 +1,7 @@
 
-use crate::additive::Function;
+use crate::additive::{Function, FUNCTIONS};
 use crate::simple::Gen as Osc;
-use crate::units::{RadPS, Hz};
+use crate::units::{RadPS, Hz, MAXHZ};
 use crate::module::*;
 
 // first-order all-pass filter with non-constant phase response
@@ -51,6 +51,17 @@ pub struct Phaser {
 }
 
 impl Phaser {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("phaser", "effect", "phaser sweeping four all-pass filters with an lfo")
+            .arg(ArgDescr::new("functype", ArgType::choice(FUNCTIONS)).descr("lfo wave shape"))
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("lfo frequency"))
+            .arg(ArgDescr::new("width", ArgType::Float(Unit::Value)).range(-1.0, 1.0).descr("width of the sweep, negative inverts the lfo"))
+            .arg(ArgDescr::new("feedback", ArgType::Float(Unit::Gain)).descr("level of the filtered signal fed back in"))
+            .input(TerminalDescr::new("in", Unit::Audio).required())
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("lfo frequency"))
+            .output(TerminalDescr::new("out", Unit::Audio))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 5 {
             return Err(format!("usage: {} functype freq width feedback", args[0]));
@@ -84,6 +95,10 @@ impl Phaser {
         self.lfo.set_freq(freq);
     }
 
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        self.lfo.set_sample_rate(rate);
+    }
+
     pub fn set_input(&mut self, v: f64) {
         self.inp = v;
     }
@@ -117,9 +132,15 @@ impl Phaser {
 impl Module for Phaser {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
         // XXX more inputs
-        (vec!["in".to_string(),
-              "freq".to_string()],
-         vec!["out".to_string()])
+        let (mut ins, outs) = Phaser::descr().terminals();
+        ins[1].max = self.lfo.rate() / 2.0;
+        ins[1].default = self.lfo.freq().0;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["phaser".to_string(), self.lfo.func().name().to_string(), self.lfo.freq().0.to_string(),
+             self.width.to_string(), self.fb.to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -127,6 +148,12 @@ impl Module for Phaser {
         None
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.inp); }
+        if idx == 1 { return Some(self.lfo.freq().0); }
+        None
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 { self.set_input(value); }
         if idx == 1 { self.set_freq(Hz(value)); }
@@ -136,9 +163,13 @@ impl Module for Phaser {
         Phaser::advance(self);
         true
     }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        Phaser::set_sample_rate(self, rate);
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("phaser", Phaser::from_cmd);
+    l.register(Phaser::descr(), Phaser::from_cmd);
 }
 
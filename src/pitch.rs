@@ -1,5 +1,5 @@
 
-use crate::units::{Samples, Hz, Cent, Sec, SAMPLE_RATE};
+use crate::units::{Samples, Hz, Cent, Sec, SAMPLE_RATE, MAXHZ};
 use crate::resampler;
 use crate::corr::{SDF, parabolic_fit_peak};
 use crate::module::*;
@@ -14,6 +14,7 @@ pub struct Pitch {
     pub size: usize, // how much data to collect into a batch
     pub overlap: usize, // how much data to keep between batches, overlap < size
     min_note: Cent,
+    rate: f64,
 
     pub period: Option<f64>, // detected period in samples whenever possible
     pub note: Option<Cent>, // the note, if a sufficiently powerful note was detected
@@ -69,10 +70,10 @@ fn first_peak_above_thresh(peaks: &Vec<(f64, f64)>, thresh: f64) -> (f64, f64) {
     unreachable!();
 }
 
-fn note_to_period(note: Cent) -> Samples {
+fn note_to_period(note: Cent, rate: f64) -> Samples {
     let Hz(freq) = note.into();
     let period = Sec(1.0 / freq);
-    period.into()
+    period.to_samples(rate)
 }
 
 const MIN_PERIODS: usize = 6; // bigger gives better accuracy but more latency and computation.
@@ -81,19 +82,24 @@ impl Pitch {
     // Pitch detector for range of notes min..max.
     // overlap is a fraction of the total window that we keep between windows.
     pub fn new(min: impl Into<Cent>, max: impl Into<Cent>, overlapfrac: f64) -> Self {
+        Self::new_at(min, max, overlapfrac, SAMPLE_RATE)
+    }
+
+    // Pitch detector for data sampled at rate.
+    pub fn new_at(min: impl Into<Cent>, max: impl Into<Cent>, overlapfrac: f64, rate: f64) -> Self {
         let min_note: Cent = min.into();
         let max_note: Cent = max.into();
         assert!(0.0 < overlapfrac && overlapfrac < 1.0);
         assert!(min_note < max_note);
 
-        let Samples(max_period) = note_to_period(min_note);
+        let Samples(max_period) = note_to_period(min_note, rate);
         let size = max_period * MIN_PERIODS;
         let overlap = (size as f64 * overlapfrac) as usize;
         assert!(overlap < size);
 
         Self {
             data: Vec::new(),
-            size, overlap, min_note,
+            size, overlap, min_note, rate,
 
             note: None,
             clarity: 0.0,
@@ -128,7 +134,7 @@ impl Pitch {
             let (lag, clarity) = first_peak_above_thresh(&peaks, PEAK_THRESH * peakval);
 
             // XXX no conversions exist yet for fractional Samples. so we hard code the conversion here
-            let period = lag / SAMPLE_RATE; // period in seconds
+            let period = lag / self.rate; // period in seconds
 
             self.clarity = clarity;
             let note: Cent = Hz(1.0 / period).into();
@@ -174,6 +180,8 @@ pub type CorrectFn = fn(Option<Cent>) -> f64;
 
 pub struct PitchCorrect {
     correctfn: CorrectFn,
+    max_note: Cent,
+    overlapfrac: f64,
     pub p: Pitch,
     outsz: usize,
     overlapsz: usize,
@@ -211,6 +219,15 @@ pub fn quantize_note(note: Option<Cent>) -> f64 {
 const RESAMPORDER: usize = 16;
 
 impl PitchCorrect {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("pitchcorrect", "effect", "detect the pitch and shift it to the nearest semitone")
+            .arg(ArgDescr::new("minfreq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("lowest detected frequency"))
+            .arg(ArgDescr::new("maxfreq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("highest detected frequency"))
+            .arg(ArgDescr::new("overlap", ArgType::Float(Unit::Value)).range(0.0, 1.0).descr("fraction of each window kept for the next"))
+            .input(TerminalDescr::new("in", Unit::Audio).required())
+            .output(TerminalDescr::new("out", Unit::Audio))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 4 {
             return Err(format!("usage: {} minfreq maxfreq overlap", args[0]));
@@ -227,15 +244,22 @@ impl PitchCorrect {
 
     // overlap specifies the overlap used in the pitch detector
     pub fn new(correctfn: CorrectFn, min: impl Into<Cent>, max: impl Into<Cent>, overlapfrac: f64) -> Self {
+        Self::new_at(correctfn, min, max, overlapfrac, SAMPLE_RATE)
+    }
+
+    pub fn new_at(correctfn: CorrectFn, min: impl Into<Cent>, max: impl Into<Cent>, overlapfrac: f64, rate: f64) -> Self {
         // We need at least two periods of overlap to properly phase match and blend
         let min_note: Cent = min.into();
-        let Samples(max_period) = note_to_period(min_note);
+        let max_note: Cent = max.into();
+        let Samples(max_period) = note_to_period(min_note, rate);
         let overlapsz = 2 * (max_period + 1);
         
-        let p = Pitch::new(min_note, max, overlapfrac);
+        let p = Pitch::new_at(min_note, max_note, overlapfrac, rate);
         let outsz = p.size - p.overlap;
         Self { 
             correctfn,
+            max_note,
+            overlapfrac,
             p,
             outsz, overlapsz,
             overlap: vec![0.0; overlapsz],
@@ -371,8 +395,12 @@ impl PitchCorrect {
 
 impl Module for PitchCorrect {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in".to_string()],
-         vec!["out".to_string()])
+        PitchCorrect::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["pitchcorrect".to_string(), Hz::from(self.p.min_note).0.to_string(),
+             Hz::from(self.max_note).0.to_string(), self.overlapfrac.to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -380,6 +408,11 @@ impl Module for PitchCorrect {
         None
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.inp); }
+        None
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 { self.inp = value; }
     }
@@ -388,8 +421,15 @@ impl Module for PitchCorrect {
         self.advance();
         true
     }
+
+    // Buffer sizes depend on the rate, so start over with a fresh corrector.
+    fn set_sample_rate(&mut self, rate: f64) {
+        if rate != self.p.rate {
+            *self = Self::new_at(self.correctfn, self.p.min_note, self.max_note, self.overlapfrac, rate);
+        }
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("pitchcorrect", PitchCorrect::from_cmd);
+    l.register(PitchCorrect::descr(), PitchCorrect::from_cmd);
 }
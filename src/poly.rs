@@ -0,0 +1,266 @@
+
+/*
+ * Polyphony: a voice allocator turns the note events of a source such as the
+ * keyboard into a frequency, gate and velocity for each of its voices.
+ * A "name poly voice [voices steal]" line in a config file builds an allocator
+ * along with a copy of the voice for each of its outputs.
+ */
+
+use std::str::FromStr;
+use crate::units::{Cent, Hz, MidiNote};
+use crate::module::*;
+
+pub const MAX_VOICES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Steal {
+    Oldest, // the voice whose note started first
+    Softest, // the voice whose note started with the lowest velocity
+    RoundRobin, // the voices in turn
+}
+
+pub const STEALS: &[&str] = &["oldest", "softest", "roundrobin"];
+
+impl Steal {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Steal::Oldest => "oldest",
+            Steal::Softest => "softest",
+            Steal::RoundRobin => "roundrobin",
+        }
+    }
+}
+
+impl FromStr for Steal {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "oldest" { return Ok(Steal::Oldest); }
+        if s == "softest" { return Ok(Steal::Softest); }
+        if s == "roundrobin" { return Ok(Steal::RoundRobin); }
+        Err(format!("unrecognized steal mode '{}'", s))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Voice {
+    note: Option<u8>, // note held down on this voice
+    freq: f64,
+    vel: f64,
+    gate: bool,
+    retrig: bool, // gate is dropped for a sample so envelopes start over
+    age: u64, // when the voice last started or released a note
+}
+
+pub struct Voices {
+    count: usize,
+    steal: Steal,
+    pending: Vec<Event>, // note events for the next sample
+    voices: Vec<Voice>,
+    clock: u64, // counts events, for the age of voices
+    next: usize, // next voice for round robin
+}
+
+fn outputs() -> Vec<TerminalDescr> {
+    let mut outs = Vec::new();
+    for v in 1 ..= MAX_VOICES {
+        outs.push(TerminalDescr::new(&format!("freq{}", v), Unit::Hz).descr("frequency of the voice's note"));
+        outs.push(TerminalDescr::new(&format!("gate{}", v), Unit::Gate).descr("high while the voice's note is held"));
+        outs.push(TerminalDescr::new(&format!("vel{}", v), Unit::Gain).range(0.0, 1.0).descr("velocity of the voice's note"));
+    }
+    outs
+}
+
+impl Voices {
+    pub fn descr() -> ModuleDescr {
+        let mut d = ModuleDescr::new("voices", "control", "share note events out among voices")
+            .arg(ArgDescr::new("voices", ArgType::Int).range(1.0, MAX_VOICES as f64).default("8").descr("number of voices to use"))
+            .arg(ArgDescr::new("steal", ArgType::choice(STEALS)).default("oldest").descr("voice to take over when all are playing"))
+            .input(TerminalDescr::new("notes", Unit::Event).required().descr("note events"));
+        for t in outputs() {
+            d = d.output(t);
+        }
+        d
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 3 {
+            return Err(format!("usage: {} voices steal", args[0]));
+        }
+        let count = parse::<usize>("voices", args[1])?;
+        if !(1 ..= MAX_VOICES).contains(&count) {
+            return Err(format!("voices must be between 1 and {}", MAX_VOICES));
+        }
+        let steal = parse::<Steal>("steal", args[2])?;
+        Ok( modref_new(Self::new(count, steal)) )
+    }
+
+    pub fn new(count: usize, steal: Steal) -> Self {
+        Voices {
+            count,
+            steal,
+            pending: Vec::new(),
+            voices: vec![Voice::default(); MAX_VOICES],
+            clock: 0,
+            next: 0,
+        }
+    }
+
+    // Voice to play a new note on.
+    fn pick(&self, note: u8) -> usize {
+        let voices = &self.voices[.. self.count];
+        if let Some(idx) = voices.iter().position(|v| v.note == Some(note)) {
+            return idx; // play it again on the same voice
+        }
+
+        // in turn from the next voice, or least recently released for the other modes
+        let free = match self.steal {
+            Steal::RoundRobin => (0 .. self.count).map(|n| (self.next + n) % self.count).find(|&idx| !voices[idx].gate),
+            _ => (0 .. self.count).filter(|&idx| !voices[idx].gate).min_by_key(|&idx| voices[idx].age),
+        };
+        if let Some(idx) = free {
+            return idx;
+        }
+
+        match self.steal {
+            Steal::Oldest => (0 .. self.count).min_by_key(|&idx| voices[idx].age).unwrap(),
+            Steal::Softest => (0 .. self.count).min_by(|&a, &b|
+                    voices[a].vel.total_cmp(&voices[b].vel).then(voices[a].age.cmp(&voices[b].age))).unwrap(),
+            Steal::RoundRobin => self.next,
+        }
+    }
+
+    fn play(&mut self, event: Event) {
+        self.clock += 1;
+        match event {
+            Event::NoteOn(n, vel) => {
+                let idx = self.pick(n);
+                let Hz(freq) = Cent::from(MidiNote(n as f64)).into();
+                let v = &mut self.voices[idx];
+                v.retrig = v.gate;
+                v.note = Some(n);
+                v.freq = freq;
+                v.vel = vel;
+                v.gate = true;
+                v.age = self.clock;
+                self.next = (idx + 1) % self.count;
+            },
+            Event::NoteOff(n) => {
+                // the voice keeps its frequency while it releases
+                if let Some(v) = self.voices[.. self.count].iter_mut().find(|v| v.note == Some(n)) {
+                    v.note = None;
+                    v.gate = false;
+                    v.retrig = false;
+                    v.age = self.clock;
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl Module for Voices {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        Voices::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["voices".to_string(), self.count.to_string(), self.steal.name().to_string()]
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        let v = self.voices.get(idx / 3)?;
+        match idx % 3 {
+            0 => Some(v.freq),
+            1 => Some(if v.gate && !v.retrig { 1.0 } else { 0.0 }),
+            _ => Some(v.vel),
+        }
+    }
+
+    fn set_input(&mut self, _idx: usize, _value: f64) {
+    }
+
+    fn send_event(&mut self, idx: usize, event: Event) {
+        if idx == 0 { self.pending.push(event); }
+    }
+
+    fn advance(&mut self) -> bool {
+        for v in self.voices.iter_mut() {
+            v.retrig = false;
+        }
+        for event in std::mem::take(&mut self.pending) {
+            self.play(event);
+        }
+        true
+    }
+}
+
+// Arguments of a "name poly voice [voices steal]" line, which the loader
+// expands into a voice allocator, the voices and a mix of their outputs.
+pub fn poly_descr() -> ModuleDescr {
+    ModuleDescr::new("poly", "control", "play note events on several copies of a voice")
+        .arg(ArgDescr::new("voice", ArgType::Str).descr("module type of a voice, with freq and gate inputs, an optional vel input, and an out output"))
+        .arg(ArgDescr::new("voices", ArgType::Int).range(1.0, MAX_VOICES as f64).default("8").descr("number of voices"))
+        .arg(ArgDescr::new("steal", ArgType::choice(STEALS)).default("oldest").descr("voice to take over when all are playing"))
+        .input(TerminalDescr::new("notes", Unit::Event).required().descr("note events"))
+        .output(TerminalDescr::new("out", Unit::Value).descr("sum of the voices"))
+}
+
+// Build a poly instance from "poly voice voices steal": a voice allocator "alloc",
+// copies of the voice "v1", "v2", ... and "mix" summing them.
+pub fn poly_expand(x: &mut Expansion, args: &Vec<&str>) -> Result<(), String> {
+    let voice = args[1];
+    let count = parse::<usize>("voices", args[2])?;
+    x.line(format!("alloc voices {} {}", count, args[3]))?;
+    x.line(format!("mix mix"))?;
+    for v in 1 ..= count {
+        x.line(format!("v{} {}", v, voice))?;
+        x.line(format!("wire alloc:freq{} v{}:freq", v, v))?;
+        x.line(format!("wire alloc:gate{} v{}:gate", v, v))?;
+        // velocity is optional for voices
+        if x.has_input(&format!("v{}", v), "vel") {
+            x.line(format!("wire alloc:vel{} v{}:vel", v, v))?;
+        }
+        x.line(format!("wire v{}:out mix:in", v))?;
+    }
+    x.line(format!("input notes alloc:notes"))?;
+    x.line(format!("output out mix:out"))
+}
+
+pub fn init(l: &mut Loader) {
+    l.register(Voices::descr(), Voices::from_cmd);
+    l.register_expansion(poly_descr(), poly_expand);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Play each event on a sample of its own.
+    fn play(v: &mut Voices, events: &[Event]) {
+        for &e in events {
+            v.send_event(0, e);
+            v.advance();
+        }
+    }
+
+    // Voice that played the note with velocity vel.
+    fn voice_with_vel(v: &Voices, vel: f64) -> Option<usize> {
+        (0 .. v.count).find(|&idx| v.get_output(idx * 3 + 2) == Some(vel))
+    }
+
+    #[test]
+    fn each_steal_mode_takes_its_voice() {
+        for (steal, want) in [(Steal::Oldest, 0), (Steal::Softest, 1), (Steal::RoundRobin, 2)] {
+            let mut v = Voices::new(3, steal);
+            // voice 0 plays the oldest note, voice 1 the softest and voice 2 is next in turn
+            play(&mut v, &[Event::NoteOn(60, 0.9), Event::NoteOn(61, 0.1), Event::NoteOn(62, 0.8),
+                           Event::NoteOff(61), Event::NoteOn(63, 0.2)]);
+            assert_eq!(voice_with_vel(&v, 0.2), Some(1), "{:?}", steal);
+            assert!((0 .. 3).all(|idx| v.get_output(idx * 3 + 1) == Some(1.0)), "{:?}", steal);
+
+            play(&mut v, &[Event::NoteOn(64, 0.5)]);
+            assert_eq!(voice_with_vel(&v, 0.5), Some(want), "{:?}", steal);
+            assert_eq!(v.get_output(want * 3), Some(Hz::from(Cent::from(MidiNote(64.0))).0), "{:?}", steal);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::convert::Into;
+use crate::units::Samples;
+use crate::speaker::{Sample, Speaker};
+use crate::module::*;
+
+// Samples captured from the speakers of a rack, shared with the capture modules.
+pub type Recording = Rc<RefCell<Vec<Sample>>>;
+
+pub fn recording_new() -> Recording {
+    Rc::new( RefCell::new(Vec::new()) )
+}
+
+// Stand-in for the speaker when rendering offline.
+// Collects the left and right inputs instead of playing them.
+// Several captures sharing one recording are mixed together.
+pub struct Capture {
+    rec: Recording,
+    pos: usize,
+
+    lvalue: f64,
+    rvalue: f64,
+}
+
+impl Capture {
+    pub fn from_cmd(rec: &Recording, args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 1 {
+            return Err(format!("usage: {}", args[0]));
+        }
+        Ok( modref_new(Self::new(rec)) )
+    }
+
+    pub fn new(rec: &Recording) -> Self {
+        Capture{ rec: rec.clone(), pos: 0, lvalue: 0.0, rvalue: 0.0 }
+    }
+}
+
+impl Module for Capture {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        Speaker::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["speaker".to_string()]
+    }
+
+    fn get_output(&self, _idx: usize) -> Option<f64> {
+        unreachable!();
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.lvalue); }
+        if idx == 1 { return Some(self.rvalue); }
+        None
+    }
+
+    fn set_input(&mut self, idx: usize, value: f64) {
+        if idx == 0 { self.lvalue = value; }
+        if idx == 1 { self.rvalue = value; }
+    }
+
+    fn advance(&mut self) -> bool {
+        let mut rec = self.rec.borrow_mut();
+        if self.pos == rec.len() {
+            rec.push(Sample{ left: 0.0, right: 0.0 });
+        }
+        rec[self.pos].left += self.lvalue;
+        rec[self.pos].right += self.rvalue;
+        self.pos += 1;
+        true
+    }
+}
+
+// Run a rack as fast as possible for some time, or until a module requests a shutdown.
+// Returns everything captured into the recording so far.
+pub fn render(rack: &mut Rack, rec: &Recording, time: impl Into<Samples>) -> Vec<Sample> {
+    rack.run(time);
+    rec.borrow().clone()
+}
@@ -1,27 +1,62 @@
 
 use std::convert::Into;
+use std::str::FromStr;
 use std::f64::consts::PI;
-use crate::units::{RadPS, Hz, MAXHZ};
-pub use crate::additive::Function;
+use crate::units::{RadPS, MAXRADPS, Hz, SAMPLE_RATE, MAXHZ};
+pub use crate::additive::{Function, FUNCTIONS};
 use crate::module::*;
 
+// How the waves with jumps and corners are made.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Mode {
+    Naive, // straight from the phase, aliasing at higher frequencies
+    Blep, // naive waves with their jumps and corners smoothed out to reduce aliasing
+}
+
+pub const MODES: &[&str] = &["naive", "blep"];
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Naive => "naive",
+            Mode::Blep => "blep",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "naive" { return Ok(Mode::Naive); }
+        if s == "blep" { return Ok(Mode::Blep); }
+        Err(format!("unrecognized mode '{}'", s))
+    }
+}
+
 // Simple function wave shape generator
-// Note: These will have low quality outputs at higher frequencies.
+// Note: In naive mode these will have low quality outputs at higher frequencies.
 // but might be well suited for some LFO operations.
+// The blep mode is band-limited well enough for audio and still cheap.
 pub struct Gen {
     phase: f64, // in radians, invariant: 0 <= phase < 2*PI
     velocity: RadPS, // invariant: 0 <= velocity <= PI
+    rate: f64, // sampling rate that velocity is relative to
 
     amp: f64,
     off: f64,
+    typ: Function,
     func: fn(f64) -> f64,
+    mode: Mode,
+    width: f64, // part of the cycle a square wave is high
+    offset: f64, // fraction of a cycle added to the phase
+    sync: SyncIn,
+    sub: SubOsc,
 
     val: f64,
 }
 
 // XXX exponential ramp-up?  exponential decay?
 // one-sided square waves?
-// variable width pulses?
 
 fn sine(phase: f64) -> f64 {
     phase.sin()
@@ -54,6 +89,118 @@ fn triangle(phase: f64) -> f64 {
     }
 }
 
+// PolyBLEP residual of a step up by 1 at t = 0, for a phase t from 0 to 1
+// that moves dt each sample. It is nonzero within a sample of the step.
+// reference: Valimaki, Pekonen and Nam, "Perceptually informed synthesis of
+// bandlimited classical waveforms using integrated polynomial interpolation"
+pub fn blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let x = 1.0 - t / dt;
+        -0.5 * x * x
+    } else if t > 1.0 - dt {
+        let x = 1.0 - (1.0 - t) / dt;
+        0.5 * x * x
+    } else {
+        0.0
+    }
+}
+
+// PolyBLAMP residual of a change in slope by 1 per sample at t = 0, the integral of blep.
+fn blamp(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let x = 1.0 - t / dt;
+        x * x * x / 6.0
+    } else if t > 1.0 - dt {
+        let x = 1.0 - (1.0 - t) / dt;
+        x * x * x / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn frac(x: f64) -> f64 {
+    x - x.floor()
+}
+
+// The sync input of an oscillator, which starts its cycle over as it goes high.
+#[derive(Clone, Debug, Default)]
+pub struct SyncIn {
+    last: f64, // value of the input on the last sample
+    since: Option<f64>, // part of a sample between going high and the next sample
+}
+
+impl SyncIn {
+    // The input goes high where it crosses 0.5, found between samples by
+    // following a straight line from its last value.
+    pub fn set(&mut self, value: f64) {
+        if value >= 0.5 && self.last < 0.5 {
+            self.since = Some((value - 0.5) / (value - self.last));
+        }
+        self.last = value;
+    }
+
+    // Go high right at the next sample.
+    pub fn trigger(&mut self) {
+        self.since = Some(0.0);
+    }
+
+    // When the input went high since the last sample, the part of a sample since then.
+    pub fn take(&mut self) -> Option<f64> {
+        self.since.take()
+    }
+
+    pub fn value(&self) -> f64 {
+        if self.last >= 0.5 { 1.0 } else { 0.0 }
+    }
+}
+
+// Square waves one and two octaves below an oscillator, flipping as its phase
+// wraps around, and its end of cycle trigger.
+// They follow the oscillator's cycle without the offset of its phase input.
+#[derive(Clone, Debug, Default)]
+pub struct SubOsc {
+    count: usize, // cycles since the last sync
+    prev: usize, // count before the cycle started, to find the jump at its start
+    eoc: bool, // a cycle ended on this sample
+}
+
+impl SubOsc {
+    pub fn advance(&mut self, wrapped: bool, synced: bool) {
+        if synced {
+            self.prev = self.count;
+            self.count = 0;
+        } else if wrapped {
+            self.prev = self.count;
+            self.count += 1;
+        }
+        self.eoc = wrapped || synced;
+    }
+
+    pub fn eoc(&self) -> f64 {
+        if self.eoc { 1.0 } else { 0.0 }
+    }
+
+    fn level(count: usize, octaves: u32) -> f64 {
+        if (count >> (octaves - 1)).is_multiple_of(2) { 1.0 } else { -1.0 }
+    }
+
+    // Square wave octaves (1 or 2) down, where t and dt are the oscillator's phase
+    // and speed as fractions of a cycle. With band_limit, its jumps are smoothed
+    // like the blep waves'.
+    pub fn get(&self, octaves: u32, t: f64, dt: f64, band_limit: bool) -> f64 {
+        let level = Self::level(self.count, octaves);
+        if !band_limit {
+            return level;
+        }
+        let jump = if t >= 0.5 {
+            Self::level(self.count + 1, octaves) - level
+        } else {
+            level - Self::level(self.prev, octaves)
+        };
+        level + jump * blep(t, dt)
+    }
+}
+
 fn get_func(typ: Function) -> fn(f64)->f64 {
     match typ {
         Function::SIN => sine,
@@ -66,25 +213,52 @@ fn get_func(typ: Function) -> fn(f64)->f64 {
 
 #[allow(dead_code)]
 impl Gen {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("osc2", "source", "cheap oscillator with aliasing, well suited to LFOs")
+            .arg(ArgDescr::new("functype", ArgType::choice(FUNCTIONS)).descr("wave shape"))
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("initial frequency"))
+            .arg(ArgDescr::new("amp", ArgType::Float(Unit::Gain)).default("1.0").descr("amplitude"))
+            .arg(ArgDescr::new("off", ArgType::Float(Unit::Value)).default("0.0").descr("offset added to the output"))
+            .arg(ArgDescr::new("mode", ArgType::choice(MODES)).default("naive").descr("naive, or blep to reduce aliasing"))
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("oscillator frequency"))
+            .input(TerminalDescr::new("width", Unit::Value).range(0.0, 1.0).default(0.5).descr("part of the cycle a square wave is high"))
+            .input(TerminalDescr::new("sync", Unit::Gate).default(0.0).descr("start the cycle over when it goes high"))
+            .input(TerminalDescr::new("phase", Unit::Value).range(0.0, 1.0).default(0.0).descr("fraction of a cycle added to the phase of out"))
+            .output(TerminalDescr::new("out", Unit::Audio).descr("off + amp * wave"))
+            .output(TerminalDescr::new("subosc1", Unit::Audio).descr("square wave one octave down"))
+            .output(TerminalDescr::new("subosc2", Unit::Audio).descr("square wave two octaves down"))
+            .output(TerminalDescr::new("eoc", Unit::Gate).descr("high for a sample when a cycle ends"))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
-        if args.len() < 3 || args.len() > 5 {
-            return Err(format!("usage: {} functype freq [amp off]", args[0]));
+        if args.len() < 3 || args.len() > 6 {
+            return Err(format!("usage: {} functype freq [amp off mode]", args[0]));
         }
         let func = parse::<Function>("functype", args[1])?;
         let freq = parse::<f64>("freq", args[2])?;
-        let amp = if args.len() >= 3 { parse::<f64>("amp", args[3])? } else { 1.0 };
-        let off = if args.len() >= 4 { parse::<f64>("off", args[4])? } else { 0.0 };
+        let amp = if args.len() >= 4 { parse::<f64>("amp", args[3])? } else { 1.0 };
+        let off = if args.len() >= 5 { parse::<f64>("off", args[4])? } else { 0.0 };
+        let mode = if args.len() >= 6 { parse::<Mode>("mode", args[5])? } else { Mode::Naive };
 
-        Ok( modref_new(Self::new_full(func, Hz(freq), amp, off)) )
+        let mut gen = Self::new_full(func, Hz(freq), amp, off);
+        gen.set_mode(mode);
+        Ok( modref_new(gen) )
     }
 
     pub fn new_full(typ: Function, freq: impl Into<RadPS>, amp: f64, off: f64) -> Self {
         Self {
             phase: 0.0,
             velocity: freq.into(),
+            rate: SAMPLE_RATE,
             amp,
             off,
+            typ,
             func: get_func(typ),
+            mode: Mode::Naive,
+            width: 0.5,
+            offset: 0.0,
+            sync: SyncIn::default(),
+            sub: SubOsc::default(),
             val: 0.0,
         }
     }
@@ -92,10 +266,23 @@ impl Gen {
         Self::new_full(typ, freq, 1.0, 0.0)
     }
 
-    // XXX pulse with pulse width parameter?
-
+    // freq is relative to the default sampling rate, like the constructor's.
     pub fn set_freq(&mut self, freq: impl Into<RadPS>) {
-        self.velocity = freq.into();
+        let RadPS(w) = freq.into();
+        self.velocity = RadPS((w * SAMPLE_RATE / self.rate).min(MAXRADPS));
+    }
+
+    pub fn freq(&self) -> Hz {
+        self.velocity.to_hz(self.rate)
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        self.velocity = RadPS((self.velocity.0 * self.rate / rate).min(MAXRADPS));
+        self.rate = rate;
     }
 
     pub fn set_phase(&mut self, theta: f64) {
@@ -111,30 +298,142 @@ impl Gen {
     }
 
     pub fn set_func(&mut self, typ: Function) {
+        self.typ = typ;
         self.func = get_func(typ);
     }
 
+    pub fn func(&self) -> Function {
+        self.typ
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    // Part of the cycle that a square wave is high, 0.5 for a square and less for narrower pulses.
+    pub fn set_width(&mut self, width: f64) {
+        self.width = width.clamp(0.0, 1.0);
+    }
+
+    // Fraction of a cycle added to the phase, for the phase input.
+    pub fn set_offset(&mut self, offset: f64) {
+        self.offset = offset;
+    }
+
+    // Start the cycle over on the next sample, for hard sync.
+    pub fn sync(&mut self) {
+        self.sync.trigger();
+    }
+
+    fn offset_phase(&self, phase: f64) -> f64 {
+        if self.offset == 0.0 { phase } else { (phase + 2.0 * PI * self.offset) % (2.0 * PI) }
+    }
+
+    fn naive(&self, phase: f64) -> f64 {
+        if self.typ == Function::SQUARE {
+            if phase <= 2.0 * PI * self.width { 1.0 } else { -1.0 }
+        } else {
+            (self.func)(phase)
+        }
+    }
+
+    // The naive wave with a correction around each jump and corner, where the
+    // jumps take the value after them so that they line up with the corrections.
+    fn blep(&self, phase: f64) -> f64 {
+        let t = phase / (2.0 * PI);
+        let dt = self.velocity.0 / (2.0 * PI);
+        match self.typ {
+            Function::SIN => self.naive(phase),
+            Function::SAWUP => 2.0 * frac(t + 0.5) - 1.0 - 2.0 * blep(frac(t + 0.5), dt),
+            Function::SAWDOWN => 1.0 - 2.0 * frac(t + 0.5) + 2.0 * blep(frac(t + 0.5), dt),
+            Function::SQUARE => {
+                let naive = if t < self.width { 1.0 } else { -1.0 };
+                naive + 2.0 * blep(t, dt) - 2.0 * blep(frac(t - self.width), dt)
+            },
+            Function::TRI => self.naive(phase) + 8.0 * dt * (blamp(frac(t + 0.25), dt) - blamp(frac(t + 0.75), dt)),
+        }
+    }
+
     pub fn advance(&mut self) -> f64 {
-        self.phase = (self.phase + self.velocity.0) % (2.0 * PI);
-        self.val = self.off + self.amp * (self.func)(self.phase);
+        let next = self.phase + self.velocity.0;
+        let synced = self.sync.take();
+        // in blep mode a sync starts the cycle over between samples, where it went high
+        let since = if self.mode == Mode::Blep { synced.unwrap_or(0.0) } else { 0.0 };
+        let before = (self.phase + (1.0 - since) * self.velocity.0) % (2.0 * PI); // phase at the sync
+        self.phase = if synced.is_some() { since * self.velocity.0 } else { next % (2.0 * PI) };
+        self.sub.advance(next >= 2.0 * PI, synced.is_some());
+
+        let phase = self.offset_phase(self.phase);
+        let v = match self.mode {
+            Mode::Naive => self.naive(phase),
+            Mode::Blep if synced.is_some() => {
+                // the jump from the old cycle to the start of the new one, whose
+                // own jumps and corrections the blep wave already has
+                let start = self.offset_phase(0.0);
+                let jump = self.naive((start + 2.0 * PI - 1e-9) % (2.0 * PI)) - self.naive(self.offset_phase(before));
+                self.blep(phase) + jump * blep(since * self.velocity.0 / (2.0 * PI), self.velocity.0 / (2.0 * PI))
+            },
+            Mode::Blep => self.blep(phase),
+        };
+        self.val = self.off + self.amp * v;
         self.val
     }
+
+    pub fn subosc(&self, octaves: u32) -> f64 {
+        let t = self.phase / (2.0 * PI);
+        let dt = self.velocity.0 / (2.0 * PI);
+        self.off + self.amp * self.sub.get(octaves, t, dt, self.mode == Mode::Blep)
+    }
+
+    pub fn eoc(&self) -> f64 {
+        self.sub.eoc()
+    }
 }
 
 impl Module for Gen {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["freq".to_string()],
-         vec!["out".to_string()])
+        let (mut ins, mut outs) = Gen::descr().terminals();
+        ins[0].max = self.rate / 2.0;
+        ins[0].default = self.velocity.to_hz(self.rate).0;
+        for out in outs[.. 3].iter_mut() {
+            out.min = self.off - self.amp.abs();
+            out.max = self.off + self.amp.abs();
+        }
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["osc2".to_string(), self.typ.name().to_string(), self.freq().0.to_string(),
+             self.amp.to_string(), self.off.to_string(), self.mode.name().to_string()]
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
-        if idx == 0 { return Some(self.val) };
-        None
+        match idx {
+            0 => Some(self.val),
+            1 => Some(self.subosc(1)),
+            2 => Some(self.subosc(2)),
+            3 => Some(self.eoc()),
+            _ => None,
+        }
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        match idx {
+            0 => Some(self.freq().0),
+            1 => Some(self.width),
+            2 => Some(self.sync.value()),
+            3 => Some(self.offset),
+            _ => None,
+        }
     }
 
     fn set_input(&mut self, idx: usize, value: f64) {
-        if idx == 0 {
-            self.velocity = Hz(value.clamp(0.0, MAXHZ)).into();
+        match idx {
+            0 => self.velocity = Hz(value).to_radps(self.rate),
+            1 => self.set_width(value),
+            2 => self.sync.set(value),
+            3 => self.set_offset(value),
+            _ => (),
         }
     }
 
@@ -142,8 +441,63 @@ impl Module for Gen {
         Gen::advance(self);
         true
     }
+
+    fn process_block(&mut self, len: usize, inputs: &[&[f64]], outputs: &mut [&mut [f64]],
+                     _events: &[Timed], _out_events: &mut Vec<Timed>) -> bool {
+        let [out, sub1, sub2, eoc] = outputs else {
+            unreachable!("osc outputs are out, subosc1, subosc2 and eoc");
+        };
+        let outs = out.iter_mut().zip(sub1.iter_mut()).zip(sub2.iter_mut()).zip(eoc.iter_mut());
+        for (n, (((out, sub1), sub2), eoc)) in outs.take(len).enumerate() {
+            for (idx, inp) in inputs.iter().enumerate() {
+                if let Some(&v) = inp.get(n) {
+                    self.set_input(idx, v);
+                }
+            }
+            *out = Gen::advance(self);
+            *sub1 = self.subosc(1);
+            *sub2 = self.subosc(2);
+            *eoc = self.eoc();
+        }
+        true
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        Gen::set_sample_rate(self, rate);
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("osc2", Gen::from_cmd);
+    l.register(Gen::descr(), Gen::from_cmd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_goes_high_between_samples() {
+        let mut sync = SyncIn::default();
+        sync.set(0.25);
+        assert_eq!(sync.take(), None);
+        sync.set(1.0); // crosses 0.5 a third of the way from 0.25
+        assert_eq!(sync.take(), Some(2.0 / 3.0));
+        assert_eq!(sync.take(), None);
+        sync.set(0.8);
+        assert_eq!(sync.take(), None);
+        assert_eq!(sync.value(), 1.0);
+    }
+
+    #[test]
+    fn sync_jump_is_smoothed_in_blep_mode() {
+        let mut gen = Gen::new(Function::SAWUP, Hz(1000.0));
+        gen.set_mode(Mode::Blep);
+        for _ in 0 .. 10 {
+            gen.advance();
+        }
+        let before = gen.advance();
+        gen.sync();
+        // halfway between the old wave and the start of the new cycle
+        assert!((gen.advance() - before / 2.0).abs() < 0.05);
+    }
 }
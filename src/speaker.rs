@@ -48,12 +48,20 @@ pub struct Speaker {
 
     #[allow(dead_code)]
     stream: cpal::Stream, // held for reference
+    fsamp: f64,
+    qsize: usize,
 
     lvalue: f64,
     rvalue: f64,
 }
 
 impl Speaker {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("speaker", "output", "play stereo audio on the default audio device")
+            .input(TerminalDescr::new("left", Unit::Audio).required())
+            .input(TerminalDescr::new("right", Unit::Audio).required())
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 1 {
             return Err(format!("usage: {}", args[0]));
@@ -90,7 +98,7 @@ impl Speaker {
         let stream = dev.build_output_stream(&cfg, pump_func, err_func).expect("cant open audio");
         stream.play().expect("error starting audio");
 
-        Speaker{ tx, rvalue: 0.0, lvalue: 0.0, stream }
+        Speaker{ tx, rvalue: 0.0, lvalue: 0.0, stream, fsamp, qsize }
     }
 
     pub fn record(&mut self, m: &mut impl Module, outp: &str, time: impl Into<Samples>) -> Result<(), String> {
@@ -113,15 +121,23 @@ impl SamplePlayer for Speaker {
 
 impl Module for Speaker {
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["left".to_string(),
-              "right".to_string()],
-         vec![])
+        Speaker::descr().terminals()
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["speaker".to_string()]
     }
 
     fn get_output(&self, _idx: usize) -> Option<f64> {
         unreachable!();
     }
 
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.lvalue); }
+        if idx == 1 { return Some(self.rvalue); }
+        None
+    }
+
     fn set_input(&mut self, idx: usize, value: f64) {
         if idx == 0 { self.lvalue = value; }
         if idx == 1 { self.rvalue = value; }
@@ -131,10 +147,17 @@ impl Module for Speaker {
         self.play(Sample{ left: self.lvalue, right: self.rvalue, });
         true
     }
+
+    // reopen the audio device at the new rate
+    fn set_sample_rate(&mut self, rate: f64) {
+        if rate != self.fsamp {
+            *self = Self::new_full(rate, self.qsize);
+        }
+    }
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("speaker", Speaker::from_cmd);
+    l.register(Speaker::descr(), Speaker::from_cmd);
 }
 
 pub struct ResamplingSpeaker {
@@ -161,12 +184,12 @@ impl SamplePlayer for ResamplingSpeaker {
 
 pub type DynSpeaker = Box<dyn SamplePlayer>;
 
+// Play audio sampled at rate.  44.1KHz audio is resampled, other rates are
+// handed to the audio device as is.
 pub fn player_at(rate: u32, qsize: usize) -> DynSpeaker {
-    assert!(SAMPLE_RATE == 48000.0);
     match rate {
         44100 => Box::new(ResamplingSpeaker::new_441_to_480(qsize)),
-        48000 => Box::new(Speaker::new_full(SAMPLE_RATE, qsize)),
-        _ => panic!("unsupported sampling rate"),
+        _ => Box::new(Speaker::new_full(rate as f64, qsize)),
     }
 }
 
@@ -2,11 +2,12 @@
 use std::convert::From;
 use std::f64::consts::PI;
 
-// XXX prefer an API where this configurable
-// but that would mean that all previous Freqs have to change
-// as soon as the sampleRate is changed...  think about this more.
+// The sampling rate is a property of each rack and can be changed at runtime.
+// The From conversions below assume the default SAMPLE_RATE, which is how
+// values are passed to module constructors. The to_* methods take an
+// explicit rate and are used by modules to derive their coefficients.
 
-pub const SAMPLE_RATE : f64 = 48000.0; // Hz
+pub const SAMPLE_RATE : f64 = 48000.0; // Hz, default
 pub const MAXRADPS : f64 = PI; // RadPS
 pub const MAXHZ : f64 = SAMPLE_RATE / 2.0;
 
@@ -20,24 +21,40 @@ pub struct Samples(pub usize);
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Default)]
 pub struct FracSamples(pub usize, pub f64);
 
+impl Samples {
+    pub fn to_sec(self, rate: f64) -> Sec {
+        Sec(self.0 as f64 / rate)
+    }
+}
+
+impl Sec {
+    pub fn to_samples(self, rate: f64) -> Samples {
+        Samples((rate * self.0) as usize)
+    }
+
+    pub fn to_frac_samples(self, rate: f64) -> FracSamples {
+        let samps = self.0 * rate;
+        let whole_samps = samps.floor();
+        let frac_samps = samps - whole_samps;
+        FracSamples(whole_samps as usize, frac_samps)
+    }
+}
+
 impl From<Samples> for Sec {
     fn from(x: Samples) -> Sec {
-        Sec(x.0 as f64 / SAMPLE_RATE)
+        x.to_sec(SAMPLE_RATE)
     }
 }
 
 impl From<Sec> for Samples {
     fn from(x: Sec) -> Samples {
-        Samples((SAMPLE_RATE * x.0) as usize)
+        x.to_samples(SAMPLE_RATE)
     }
 }
 
 impl From<Sec> for FracSamples {
     fn from(x: Sec) -> FracSamples {
-        let samps = x.0 * SAMPLE_RATE;
-        let whole_samps = samps.floor();
-        let frac_samps = samps - whole_samps;
-        FracSamples(whole_samps as usize, frac_samps)
+        x.to_frac_samples(SAMPLE_RATE)
     }
 }
 
@@ -55,17 +72,29 @@ pub struct Hz(pub f64);
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Default)]
 pub struct RadPS(pub f64);
 
+impl RadPS {
+    pub fn to_hz(self, rate: f64) -> Hz {
+        Hz( self.0 * (rate / (2.0 * PI)) )
+    }
+}
+
+impl Hz {
+    // Frequencies above nyquist, which depends on the rate, are held at nyquist.
+    pub fn to_radps(self, rate: f64) -> RadPS {
+        let mfreq = self.0 * (2.0 * PI / rate);
+        RadPS(mfreq.min(MAXRADPS))
+    }
+}
+
 impl From<RadPS> for Hz {
     fn from(x: RadPS) -> Self {
-        Hz( x.0 * (SAMPLE_RATE / (2.0 * PI)) )
+        x.to_hz(SAMPLE_RATE)
     }
 }
 
 impl From<Hz> for RadPS {
     fn from(hz: Hz) -> Self {
-        let mfreq = hz.0 * (2.0 * PI / SAMPLE_RATE);
-        debug_assert!(mfreq <= MAXRADPS);
-        RadPS(mfreq)
+        hz.to_radps(SAMPLE_RATE)
     }
 }
 
@@ -115,3 +144,97 @@ impl From<MidiNote> for Cent {
         Cent((x.0 - 69.0) * 100.0)
     }
 }
+
+/*
+ * Literal values in config files, which may carry a unit:
+ * 50ms, 2s, 440Hz, 1.2kHz, -6dB, 300c (cents above A440),
+ * note names such as A4 or C#3, and note lengths such as 1/8@120bpm.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    Plain(f64),
+    Time(Sec),
+    Freq(Hz),
+    Db(f64),
+}
+
+fn parse_num(s: &str) -> Result<f64, String> {
+    s.parse().map_err(|_| format!("can't parse number '{}'", s))
+}
+
+// Note name with optional sharp or flat and an octave, with C4 as middle C.
+fn parse_note(s: &str) -> Option<Hz> {
+    let mut cs = s.chars();
+    let base = match cs.next()? {
+        'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5, 'G' => 7, 'A' => 9, 'B' => 11,
+        _ => return None,
+    };
+    let rest = cs.as_str();
+    let (accidental, octave) = if let Some(o) = rest.strip_prefix('#') {
+        (1, o)
+    } else if let Some(o) = rest.strip_prefix('b') {
+        (-1, o)
+    } else {
+        (0, rest)
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let note = MidiNote((12 * (octave + 1) + base + accidental) as f64);
+    Some(Hz::from(Cent::from(note)))
+}
+
+// Length of a note such as 1/8 at a tempo in beats per minute, with a beat being a quarter note.
+fn parse_note_length(len: &str, bpm: &str) -> Result<Sec, String> {
+    let (num, den) = match len.split_once('/') {
+        Some((num, den)) => (parse_num(num)?, parse_num(den)?),
+        None => (parse_num(len)?, 1.0),
+    };
+    let bpm = parse_num(bpm)?;
+    if den == 0.0 || bpm <= 0.0 {
+        return Err(format!("bad note length '{}@{}bpm'", len, bpm));
+    }
+    Ok(Sec(4.0 * num / den * 60.0 / bpm))
+}
+
+impl std::str::FromStr for Quantity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hz) = parse_note(s) {
+            return Ok(Quantity::Freq(hz));
+        }
+        if let Some((len, tempo)) = s.split_once('@') {
+            let bpm = tempo.strip_suffix("bpm").ok_or(format!("tempo must be in bpm in '{}'", s))?;
+            return Ok(Quantity::Time(parse_note_length(len, bpm)?));
+        }
+
+        // longest suffixes first, so "ms" isn't taken for "s"
+        let lower = s.to_lowercase();
+        let q = if let Some(v) = lower.strip_suffix("khz") {
+            Quantity::Freq(Hz(parse_num(v)? * 1000.0))
+        } else if let Some(v) = lower.strip_suffix("hz") {
+            Quantity::Freq(Hz(parse_num(v)?))
+        } else if let Some(v) = lower.strip_suffix("ms") {
+            Quantity::Time(Sec(parse_num(v)? / 1000.0))
+        } else if let Some(v) = lower.strip_suffix("db") {
+            Quantity::Db(parse_num(v)?)
+        } else if let Some(v) = lower.strip_suffix('s') {
+            Quantity::Time(Sec(parse_num(v)?))
+        } else if let Some(v) = lower.strip_suffix('c') {
+            Quantity::Freq(Hz::from(Cent(parse_num(v)?)))
+        } else {
+            Quantity::Plain(parse_num(s)?)
+        };
+        Ok(q)
+    }
+}
+
+impl Quantity {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Quantity::Plain(_) => "number",
+            Quantity::Time(_) => "time",
+            Quantity::Freq(_) => "frequency",
+            Quantity::Db(_) => "level in dB",
+        }
+    }
+}
@@ -8,6 +8,13 @@ pub struct Mult {
 }
 
 impl Mult {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("mult", "math", "multiply two signals")
+            .input(TerminalDescr::new("in1", Unit::Value).required())
+            .input(TerminalDescr::new("in2", Unit::Value).required())
+            .output(TerminalDescr::new("out", Unit::Value))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 1 {
             return Err(format!("usage: {}", args[0]));
@@ -21,9 +28,18 @@ impl Mult {
 }
 
 impl Module for Mult {
+    fn get_args(&self) -> Vec<String> {
+        vec!["mult".to_string()]
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.in1); }
+        if idx == 1 { return Some(self.in2); }
+        None
+    }
+
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in1".to_string(), "in2".to_string()],
-         vec!["out".to_string()])
+        Mult::descr().terminals()
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -38,6 +54,17 @@ impl Module for Mult {
         self.out = self.in1 * self.in2;
         true
     }
+
+    fn process_block(&mut self, len: usize, inputs: &[&[f64]], outputs: &mut [&mut [f64]],
+                     _events: &[Timed], _out_events: &mut Vec<Timed>) -> bool {
+        for (n, out) in outputs[0][.. len].iter_mut().enumerate() {
+            if let Some(&v) = inputs[0].get(n) { self.in1 = v; }
+            if let Some(&v) = inputs[1].get(n) { self.in2 = v; }
+            self.out = self.in1 * self.in2;
+            *out = self.out;
+        }
+        true
+    }
 }
 
 pub struct Add {
@@ -47,6 +74,13 @@ pub struct Add {
 }
 
 impl Add {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("add", "math", "add two signals")
+            .input(TerminalDescr::new("in1", Unit::Value).required())
+            .input(TerminalDescr::new("in2", Unit::Value).required())
+            .output(TerminalDescr::new("out", Unit::Value))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 1 {
             return Err(format!("usage: {}", args[0]));
@@ -60,9 +94,18 @@ impl Add {
 }
 
 impl Module for Add {
+    fn get_args(&self) -> Vec<String> {
+        vec!["add".to_string()]
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.in1); }
+        if idx == 1 { return Some(self.in2); }
+        None
+    }
+
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in1".to_string(), "in2".to_string()],
-         vec!["out".to_string()])
+        Add::descr().terminals()
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -85,6 +128,12 @@ pub struct Inv {
 }
 
 impl Inv {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("inv", "math", "negate a signal")
+            .input(TerminalDescr::new("in", Unit::Value).required())
+            .output(TerminalDescr::new("out", Unit::Value))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 1 {
             return Err(format!("usage: {}", args[0]));
@@ -98,9 +147,16 @@ impl Inv {
 }
 
 impl Module for Inv {
+    fn get_args(&self) -> Vec<String> {
+        vec!["inv".to_string()]
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { Some(self.inp) } else { None }
+    }
+
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in".to_string()],
-         vec!["out".to_string()])
+        Inv::descr().terminals()
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -116,11 +172,83 @@ impl Module for Inv {
     }
 }
 
+// Sum of all of the wires into its input, such as the voices of a poly line.
+pub struct Mix {
+    inp: f64,
+    out: f64,
+}
+
+impl Mix {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("mix", "math", "sum the signals wired to its input")
+            .input(TerminalDescr::new("in", Unit::Value).required())
+            .output(TerminalDescr::new("out", Unit::Value))
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 1 {
+            return Err(format!("usage: {}", args[0]));
+        }
+        Ok( modref_new(Self::new()) )
+    }
+
+    pub fn new() -> Self {
+        Self{ inp: 0.0, out: 0.0 }
+    }
+}
+
+impl Module for Mix {
+    fn get_args(&self) -> Vec<String> {
+        vec!["mix".to_string()]
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { Some(self.inp) } else { None }
+    }
+
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        Mix::descr().terminals()
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { Some(self.out) } else { None }
+    }
+
+    fn set_input(&mut self, idx: usize, value: f64) {
+        if idx == 0 { self.inp = value; }
+    }
+    fn advance(&mut self) -> bool {
+        // the rack already summed the wires
+        self.out = self.inp;
+        true
+    }
+
+    fn process_block(&mut self, len: usize, inputs: &[&[f64]], outputs: &mut [&mut [f64]],
+                     _events: &[Timed], _out_events: &mut Vec<Timed>) -> bool {
+        if inputs[0].is_empty() {
+            outputs[0][.. len].fill(self.inp);
+        } else {
+            outputs[0][.. len].copy_from_slice(&inputs[0][.. len]);
+            if let Some(&v) = inputs[0][.. len].last() {
+                self.inp = v;
+            }
+        }
+        self.out = self.inp;
+        true
+    }
+}
+
 pub struct Const {
     out: f64,
 }
 
 impl Const {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("const", "source", "constant value")
+            .arg(ArgDescr::new("val", ArgType::Float(Unit::Value)).descr("the value"))
+            .output(TerminalDescr::new("out", Unit::Value))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 2 {
             return Err(format!("usage: {} val", args[0]));
@@ -135,9 +263,15 @@ impl Const {
 }
 
 impl Module for Const {
+    fn get_args(&self) -> Vec<String> {
+        vec!["const".to_string(), self.out.to_string()]
+    }
+
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec![],
-         vec!["out".to_string()])
+        let (ins, mut outs) = Const::descr().terminals();
+        outs[0].min = self.out;
+        outs[0].max = self.out;
+        (ins, outs)
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -163,6 +297,14 @@ pub struct Bias {
 }
 
 impl Bias {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("bias", "math", "scale a signal and add an offset")
+            .arg(ArgDescr::new("off", ArgType::Float(Unit::Value)).descr("offset added after scaling"))
+            .arg(ArgDescr::new("width", ArgType::Float(Unit::Value)).descr("scale factor"))
+            .input(TerminalDescr::new("in", Unit::Value).required())
+            .output(TerminalDescr::new("out", Unit::Value).descr("off + width * in"))
+    }
+
     pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
         if args.len() != 3 {
             return Err(format!("usage: {} off width", args[0]));
@@ -184,9 +326,16 @@ impl Bias {
 }
 
 impl Module for Bias {
+    fn get_args(&self) -> Vec<String> {
+        vec!["bias".to_string(), self.off.to_string(), self.width.to_string()]
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { Some(self.inp) } else { None }
+    }
+
     fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
-        (vec!["in".to_string()],
-         vec!["out".to_string()])
+        Bias::descr().terminals()
     }
 
     fn get_output(&self, idx: usize) -> Option<f64> {
@@ -204,9 +353,10 @@ impl Module for Bias {
 }
 
 pub fn init(l: &mut Loader) {
-    l.register("mult", Mult::from_cmd);
-    l.register("add", Add::from_cmd);
-    l.register("inv", Inv::from_cmd);
-    l.register("const", Const::from_cmd);
-    l.register("bias", Bias::from_cmd);
+    l.register(Mult::descr(), Mult::from_cmd);
+    l.register(Add::descr(), Add::from_cmd);
+    l.register(Inv::descr(), Inv::from_cmd);
+    l.register(Mix::descr(), Mix::from_cmd);
+    l.register(Const::descr(), Const::from_cmd);
+    l.register(Bias::descr(), Bias::from_cmd);
 }
@@ -1,8 +1,23 @@
 
+use std::str::FromStr;
 use std::fs::File;
-use wav::{self, bit_depth::BitDepth};
+use wav::{self, bit_depth::BitDepth, header::{Header, WAV_FORMAT_PCM, WAV_FORMAT_IEEE_FLOAT}};
 pub use crate::speaker::Sample;
 
+// Sample formats for writing wav files.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum WavFormat { #[default] S16, S24, F32 }
+
+impl FromStr for WavFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "16" { return Ok(WavFormat::S16); }
+        if s == "24" { return Ok(WavFormat::S24); }
+        if s == "32f" { return Ok(WavFormat::F32); }
+        Err(format!("unrecognized wav format '{}'", s))
+    }
+}
+
 fn cvt_pairs<T: Copy, F: Fn(T) -> f64>(vs: &Vec<T>, cvt: F) -> Vec<Sample> {
     (0..vs.len())
         .step_by(2)
@@ -14,7 +29,7 @@ fn convert_samples(wavsamps: &BitDepth) -> Vec<Sample> {
     match wavsamps {
         BitDepth::Eight(vs) => cvt_pairs(vs, |vu8| (vu8 as f64 - 128.0) / 128.0),
         BitDepth::Sixteen(vs) => cvt_pairs(vs, |vi16| vi16 as f64 / 32768.0),
-        BitDepth::TwentyFour(vs) => cvt_pairs(vs, |vi32| vi32 as f64 / 16777216.0),
+        BitDepth::TwentyFour(vs) => cvt_pairs(vs, |vi32| vi32 as f64 / 2147483648.0), // in the top three bytes
         BitDepth::ThirtyTwoFloat(vs) => cvt_pairs(vs, |vf32| vf32 as f64),
         BitDepth::Empty => panic!("can't process empty samples"),
     }
@@ -33,3 +48,48 @@ pub fn read_wav(path: &str) -> (u32, Vec<Sample>) {
     assert!(hdr.channel_count == 2);
     (hdr.sampling_rate, convert_samples(&dat))
 }
+
+fn cvt_mono<T: Copy, F: Fn(T) -> f64>(vs: &[T], chans: usize, cvt: F) -> Vec<f64> {
+    vs.chunks_exact(chans)
+        .map(|frame| frame.iter().map(|&v| cvt(v)).sum::<f64>() / chans as f64)
+        .collect()
+}
+
+// Read a wav file with any number of channels, mixed down to one.
+pub fn read_wav_mono(path: &str) -> Result<(u32, Vec<f64>), String> {
+    let mut inp = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let (hdr, dat) = wav::read(&mut inp).map_err(|e| format!("{}: {}", path, e))?;
+    let chans = hdr.channel_count as usize;
+    if chans == 0 {
+        return Err(format!("{}: no channels", path));
+    }
+    let samps = match dat {
+        BitDepth::Eight(vs) => cvt_mono(&vs, chans, |vu8| (vu8 as f64 - 128.0) / 128.0),
+        BitDepth::Sixteen(vs) => cvt_mono(&vs, chans, |vi16| vi16 as f64 / 32768.0),
+        BitDepth::TwentyFour(vs) => cvt_mono(&vs, chans, |vi32| vi32 as f64 / 2147483648.0),
+        BitDepth::ThirtyTwoFloat(vs) => cvt_mono(&vs, chans, |vf32| vf32 as f64),
+        BitDepth::Empty => return Err(format!("{}: no samples", path)),
+    };
+    Ok((hdr.sampling_rate, samps))
+}
+
+fn flatten<T, F: Fn(f64) -> T>(samps: &[Sample], cvt: F) -> Vec<T> {
+    samps.iter()
+        .flat_map(|s| [cvt(s.left), cvt(s.right)])
+        .collect()
+}
+
+// Write stereo samples to a wav file.
+pub fn write_wav(path: &str, rate: u32, samps: &[Sample], fmt: WavFormat) -> Result<(), String> {
+    let (hdr, dat) = match fmt {
+        WavFormat::S16 => (Header::new(WAV_FORMAT_PCM, 2, rate, 16),
+                           BitDepth::Sixteen(flatten(samps, |x| (32767.0 * x.clamp(-1.0, 1.0)) as i16))),
+        // the wav crate keeps 24-bit samples in the top three bytes of an i32
+        WavFormat::S24 => (Header::new(WAV_FORMAT_PCM, 2, rate, 24),
+                           BitDepth::TwentyFour(flatten(samps, |x| ((8388607.0 * x.clamp(-1.0, 1.0)) as i32) << 8))),
+        WavFormat::F32 => (Header::new(WAV_FORMAT_IEEE_FLOAT, 2, rate, 32),
+                           BitDepth::ThirtyTwoFloat(flatten(samps, |x| x as f32))),
+    };
+    let mut out = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    wav::write(hdr, &dat, &mut out).map_err(|e| format!("{}: {}", path, e))
+}
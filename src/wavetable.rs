@@ -0,0 +1,361 @@
+
+/*
+ * Wavetable oscillator: plays single cycle frames, band-limited with a copy
+ * of each frame per octave, and morphs between frames with its position input.
+ *
+ * A table comes from a wav file holding its frames one after the other, or
+ * from harmonic lists. Frames in a wav file are 2048 samples unless the file
+ * has a "clm " chunk saying otherwise, as Serum writes them.
+ */
+
+use std::fs;
+use num_complex::Complex;
+use rustfft::FftPlanner;
+use crate::additive::{self, Function, HarmonicParam};
+use crate::units::{Hz, SAMPLE_RATE, MAXHZ};
+use crate::wav::read_wav_mono;
+use crate::module::*;
+
+const MIN_LEN: usize = 64; // shortest copy of a frame
+
+// Frame size from a wav file's "clm " chunk, whose text starts "<!>2048".
+fn clm_frame(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 12 || &buf[0..4] != b"RIFF" || &buf[8..12] != b"WAVE" {
+        return None;
+    }
+    let mut pos = 12;
+    while pos + 8 <= buf.len() {
+        let id = &buf[pos .. pos + 4];
+        let size = u32::from_le_bytes([buf[pos + 4], buf[pos + 5], buf[pos + 6], buf[pos + 7]]) as usize;
+        let dat = &buf[pos + 8 .. (pos + 8 + size).min(buf.len())];
+        if id == b"clm " && dat.starts_with(b"<!>") {
+            let digits: String = dat[3..].iter().map(|&b| b as char).take_while(|c| c.is_ascii_digit()).collect();
+            return digits.parse().ok();
+        }
+        pos += 8 + size + (size & 1); // chunks are padded to even sizes
+    }
+    None
+}
+
+// Parse a harmonic list like "1:1,2:0.5,3:0.33", giving each harmonic's sine amplitude.
+fn parse_harmonics(s: &str) -> Result<Vec<HarmonicParam>, String> {
+    s.split(',').map(|h| {
+        let (k, amp) = h.split_once(':').ok_or(format!("harmonic '{}' should be k:amp", h))?;
+        let k = parse::<usize>("harmonic", k)?;
+        let amp = parse::<f64>("amplitude", amp)?;
+        Ok(HarmonicParam{ k, amp })
+    }).collect()
+}
+
+// A table of frames, each with a copy per octave that keeps half as many
+// harmonics as the copy before it.
+pub struct Table {
+    frames: Vec<Vec<Vec<f64>>>, // frames, levels, samples
+    harmonics: usize, // highest harmonic kept at level 0
+}
+
+impl Table {
+    // Build a table from the spectra of its frames, each of size bins.
+    fn from_spectra(spectra: Vec<Vec<Complex<f64>>>, bins: usize) -> Self {
+        let harmonics = bins / 2;
+        let mut levels = 1;
+        while harmonics >> levels > 0 {
+            levels += 1;
+        }
+
+        let mut planner = FftPlanner::new();
+        let frames = spectra.iter().map(|spec| {
+            (0 .. levels).map(|level| {
+                let h = harmonics >> level;
+                // oversampled so that interpolating between samples hardly aliases
+                let len = (32 * h).clamp(MIN_LEN, 2 * bins.max(MIN_LEN));
+                let mut buf = vec![Complex::new(0.0, 0.0); len];
+                buf[0] = spec[0];
+                for k in 1 ..= h {
+                    if 2 * k == bins {
+                        // the nyquist bin stands for both k and bins - k, so it's split between them
+                        buf[k] = spec[k] / 2.0;
+                        buf[len - k] = spec[k] / 2.0;
+                    } else if k < len - k {
+                        buf[k] = spec[k];
+                        buf[len - k] = spec[bins - k];
+                    } else if k == len - k {
+                        buf[k] = spec[k];
+                    }
+                }
+                planner.plan_fft_inverse(len).process(&mut buf);
+                buf.iter().map(|c| c.re).collect()
+            }).collect()
+        }).collect();
+        Table { frames, harmonics }
+    }
+
+    // Build a table from frames of samples laid end to end.
+    pub fn from_samples(samps: &[f64], frame: usize) -> Result<Self, String> {
+        if frame < 2 {
+            return Err(format!("frame size {} is too small", frame));
+        }
+        if samps.len() < frame || !samps.len().is_multiple_of(frame) {
+            return Err(format!("{} samples aren't a whole number of {} sample frames", samps.len(), frame));
+        }
+        let fft = FftPlanner::new().plan_fft_forward(frame);
+        let spectra = samps.chunks(frame).map(|f| {
+            let mut buf: Vec<Complex<f64>> = f.iter().map(|&x| Complex::new(x / frame as f64, 0.0)).collect();
+            fft.process(&mut buf);
+            buf
+        }).collect();
+        Ok(Self::from_spectra(spectra, frame))
+    }
+
+    // Build a table with a frame for each harmonic series, summing
+    // amp * sin(k * phase) as additive::Gen does. Harmonics above frame / 2 are left out.
+    pub fn from_harmonics(series: &[Vec<HarmonicParam>], frame: usize) -> Self {
+        let spectra = series.iter().map(|params| {
+            let mut buf = vec![Complex::new(0.0, 0.0); frame];
+            for p in params.iter().filter(|p| p.k > 0 && 2 * p.k < frame) {
+                buf[p.k] += Complex::new(0.0, -p.amp / 2.0);
+                buf[frame - p.k] += Complex::new(0.0, p.amp / 2.0);
+            }
+            buf
+        }).collect();
+        Self::from_spectra(spectra, frame)
+    }
+
+    // Load the frames of a wav file, frame samples each unless the file says otherwise.
+    pub fn load(fname: &str, frame: usize) -> Result<Self, String> {
+        let buf = fs::read(fname).map_err(|e| format!("{}: {}", fname, e))?;
+        let frame = clm_frame(&buf).unwrap_or(frame);
+        let (_, samps) = read_wav_mono(fname)?;
+        Self::from_samples(&samps, frame).map_err(|e| format!("{}: {}", fname, e))
+    }
+
+    // Table named on a config line: frames of harmonic lists separated by "/",
+    // the name of a wave shape, or a wav file.
+    pub fn from_arg(arg: &str, frame: usize) -> Result<Self, String> {
+        if arg.contains(':') {
+            let series = arg.split('/').map(parse_harmonics).collect::<Result<Vec<_>, _>>()?;
+            return Ok(Self::from_harmonics(&series, frame));
+        }
+        if let Ok(func) = arg.parse::<Function>() {
+            return Ok(Self::from_harmonics(&[additive::get_series(func, frame / 2)], frame));
+        }
+        Self::load(arg, frame)
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn levels(&self) -> usize {
+        self.frames[0].len()
+    }
+
+    // Copy to play at a frequency, in cycles per sample, without aliasing.
+    pub fn level(&self, freq: f64) -> usize {
+        let mut level = 0;
+        while level + 1 < self.levels() && (self.harmonics >> level) as f64 * freq > 0.5 {
+            level += 1;
+        }
+        level
+    }
+
+    fn lookup(&self, frame: usize, level: usize, phase: f64) -> f64 {
+        let tab = &self.frames[frame][level];
+        let x = phase * tab.len() as f64;
+        let i = x as usize % tab.len();
+        let frac = x - x.floor();
+        tab[i] + frac * (tab[(i + 1) % tab.len()] - tab[i])
+    }
+
+    // Value at a phase from 0 to 1 and a position from 0 to 1 across the frames.
+    pub fn get(&self, level: usize, position: f64, phase: f64) -> f64 {
+        let pos = position.clamp(0.0, 1.0) * (self.frames() - 1) as f64;
+        let frame = (pos as usize).min(self.frames() - 1);
+        let frac = pos - frame as f64;
+        let a = self.lookup(frame, level, phase);
+        if frac == 0.0 {
+            return a;
+        }
+        a + frac * (self.lookup(frame + 1, level, phase) - a)
+    }
+}
+
+pub struct Wavetable {
+    name: String, // table as named on the config line
+    frame: usize,
+    table: Table,
+    freq: Hz,
+    position: f64,
+    rate: f64,
+    phase: f64, // fraction of a cycle, invariant: 0 <= phase < 1
+    level: usize,
+    val: f64,
+}
+
+impl Wavetable {
+    pub fn descr() -> ModuleDescr {
+        ModuleDescr::new("wavetable", "source", "band-limited oscillator playing and morphing between single cycle frames")
+            .arg(ArgDescr::new("table", ArgType::Str).descr("wav file of frames, wave shape, or harmonic lists like 1:1,2:0.5/1:1,3:0.3"))
+            .arg(ArgDescr::new("freq", ArgType::Float(Unit::Hz)).range(0.0, MAXHZ).descr("initial frequency"))
+            .arg(ArgDescr::new("frame", ArgType::Int).range(2.0, f64::INFINITY).default("2048").descr("samples in each frame"))
+            .input(TerminalDescr::new("freq", Unit::Hz).range(0.0, MAXHZ).descr("oscillator frequency"))
+            .input(TerminalDescr::new("position", Unit::Value).range(0.0, 1.0).default(0.0).descr("place between the first and last frame"))
+            .output(TerminalDescr::new("out", Unit::Audio).range(-1.0, 1.0))
+    }
+
+    pub fn from_cmd(args: &Vec<&str>) -> Result<ModRef, String> {
+        if args.len() != 4 {
+            return Err(format!("usage: {} table freq frame", args[0]));
+        }
+        let freq = parse::<f64>("freq", args[2])?;
+        let frame = parse::<usize>("frame", args[3])?;
+        if frame < 2 {
+            return Err(format!("frame size {} is too small", frame));
+        }
+        let table = Table::from_arg(args[1], frame)?;
+        Ok( modref_new(Self::new(args[1], frame, table, Hz(freq))) )
+    }
+
+    pub fn new(name: &str, frame: usize, table: Table, freq: Hz) -> Self {
+        let mut w = Wavetable {
+            name: name.to_owned(),
+            frame,
+            table,
+            freq,
+            position: 0.0,
+            rate: SAMPLE_RATE,
+            phase: 0.0,
+            level: 0,
+            val: 0.0,
+        };
+        w.set_freq(freq);
+        w
+    }
+
+    pub fn set_freq(&mut self, freq: Hz) {
+        self.freq = freq;
+        self.level = self.table.level(freq.0 / self.rate);
+    }
+
+    pub fn set_position(&mut self, position: f64) {
+        self.position = position.clamp(0.0, 1.0);
+    }
+
+    pub fn advance(&mut self) -> f64 {
+        self.phase = (self.phase + self.freq.0 / self.rate).fract();
+        self.val = self.table.get(self.level, self.position, self.phase);
+        self.val
+    }
+}
+
+impl Module for Wavetable {
+    fn get_terminals(&self) -> (Vec<TerminalDescr>, Vec<TerminalDescr>) {
+        let (mut ins, outs) = Wavetable::descr().terminals();
+        ins[0].max = self.rate / 2.0;
+        ins[0].default = self.freq.0;
+        (ins, outs)
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        vec!["wavetable".to_string(), self.name.clone(), self.freq.0.to_string(), self.frame.to_string()]
+    }
+
+    fn get_output(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.val); }
+        None
+    }
+
+    fn get_input(&self, idx: usize) -> Option<f64> {
+        if idx == 0 { return Some(self.freq.0); }
+        if idx == 1 { return Some(self.position); }
+        None
+    }
+
+    fn set_input(&mut self, idx: usize, value: f64) {
+        if idx == 0 { self.set_freq(Hz(value)); }
+        if idx == 1 { self.set_position(value); }
+    }
+
+    fn advance(&mut self) -> bool {
+        Wavetable::advance(self);
+        true
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        self.set_freq(self.freq);
+    }
+}
+
+pub fn init(l: &mut Loader) {
+    l.register(Wavetable::descr(), Wavetable::from_cmd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    // A RIFF WAVE file holding the given chunks.
+    fn riff(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for (id, dat) in chunks {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(dat.len() as u32).to_le_bytes());
+            body.extend_from_slice(dat);
+            if dat.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let mut buf = b"RIFF".to_vec();
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend(body);
+        buf
+    }
+
+    #[test]
+    fn clm_chunk_gives_the_frame_size() {
+        assert_eq!(clm_frame(&riff(&[(b"fmt ", b"odd"), (b"clm ", b"<!>256 10000000 wavetable")])), Some(256));
+        assert_eq!(clm_frame(&riff(&[(b"fmt ", b"0123"), (b"data", b"")])), None);
+        assert_eq!(clm_frame(&riff(&[(b"clm ", b"256")])), None);
+        assert_eq!(clm_frame(b"RIFX\0\0\0\0WAVEclm \x07\0\0\0<!>2048"), None);
+    }
+
+    #[test]
+    fn level_keeps_harmonics_below_nyquist() {
+        let t = Table::from_arg("sawup", 2048).unwrap();
+        assert_eq!(t.level(0.0001), 0);
+        assert_eq!(t.level(0.001), 2); // 256 harmonics at 0.001 cycles per sample
+        assert_eq!(t.level(0.5), t.levels() - 1);
+        for freq in [0.0003, 0.002, 0.01, 0.07, 0.2] {
+            let level = t.level(freq);
+            assert!((t.harmonics >> level) as f64 * freq <= 0.5, "{}", freq);
+            // and the level before would alias
+            if level > 0 {
+                assert!((t.harmonics >> (level - 1)) as f64 * freq > 0.5, "{}", freq);
+            }
+        }
+    }
+
+    #[test]
+    fn position_morphs_between_frames() {
+        let t = Table::from_arg("1:1/2:0.5", 256).unwrap();
+        for n in 0 .. 16 {
+            let phase = n as f64 / 16.0;
+            let a = (2.0 * PI * phase).sin();
+            let b = 0.5 * (4.0 * PI * phase).sin();
+            assert!((t.get(0, 0.0, phase) - a).abs() < 1e-3, "{}", phase);
+            assert!((t.get(0, 1.0, phase) - b).abs() < 1e-3, "{}", phase);
+            assert!((t.get(0, 0.25, phase) - (0.75 * a + 0.25 * b)).abs() < 1e-3, "{}", phase);
+        }
+    }
+
+    #[test]
+    fn nyquist_keeps_its_level() {
+        let samps: Vec<f64> = (0 .. 8).map(|n| if n % 2 == 0 { 0.5 } else { -0.5 }).collect();
+        let t = Table::from_samples(&samps, 8).unwrap();
+        for (n, x) in samps.iter().enumerate() {
+            assert!((t.get(0, 0.0, n as f64 / 8.0) - x).abs() < 1e-9, "{}", n);
+        }
+    }
+}
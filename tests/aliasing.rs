@@ -0,0 +1,151 @@
+/*
+ * The band-limited osc2 waves alias much less than the naive waves,
+ * and wavetables built from the harmonics below nyquist hardly alias at all.
+ * Run with --nocapture to see the aliasing of the additive waves next to them.
+ */
+
+use std::f64::consts::PI;
+use num_complex::Complex;
+use rustfft::FftPlanner;
+use rau::module::Module;
+use rau::units::{Hz, SAMPLE_RATE};
+use rau::{additive, simple};
+use rau::wavetable::{Table, Wavetable};
+
+const N: usize = 16384;
+const SKIP: usize = 1000;
+
+// Power outside of the wave's harmonics, relative to all of it, in dB.
+fn alias_db(samples: &[f64], freq: f64, all_harmonics: bool) -> f64 {
+    let mut buf: Vec<Complex<f64>> = samples.iter().enumerate()
+        .map(|(n, x)| {
+            // blackman-harris window, leaking little into far away bins
+            let w = 2.0 * PI * n as f64 / N as f64;
+            let win = 0.35875 - 0.48829 * w.cos() + 0.14128 * (2.0 * w).cos() - 0.01168 * (3.0 * w).cos();
+            Complex::new(x * win, 0.0)
+        })
+        .collect();
+    FftPlanner::new().plan_fft_forward(N).process(&mut buf);
+
+    let bin_hz = SAMPLE_RATE / N as f64;
+    let mut harmonic = vec![false; N / 2];
+    let mut k = 0; // including any offset
+    while k as f64 * freq < SAMPLE_RATE / 2.0 {
+        if all_harmonics || k % 2 == 1 || k == 0 {
+            let center = (k as f64 * freq / bin_hz).round() as isize;
+            for b in center - 6 ..= center + 6 {
+                if b >= 0 && (b as usize) < N / 2 {
+                    harmonic[b as usize] = true;
+                }
+            }
+        }
+        k += 1;
+    }
+
+    let mut total = 0.0;
+    let mut alias = 0.0;
+    for (b, c) in buf[.. N / 2].iter().enumerate() {
+        let p = c.norm_sqr();
+        total += p;
+        if !harmonic[b] {
+            alias += p;
+        }
+    }
+    10.0 * (alias / total).log10()
+}
+
+fn simple_samples(typ: simple::Function, freq: f64, mode: simple::Mode, width: f64) -> Vec<f64> {
+    let mut gen = simple::Gen::new(typ, Hz(freq));
+    gen.set_mode(mode);
+    gen.set_width(width);
+    (0 .. SKIP + N).map(|_| gen.advance()).skip(SKIP).collect()
+}
+
+// A wave synced to a master at freq, through a sync input that crosses 0.5 as
+// the master's cycle starts over, so that the syncs fall between samples.
+fn synced_samples(mut gen: impl Module, freq: f64, sync: usize) -> Vec<f64> {
+    let dt = freq / SAMPLE_RATE;
+    (0 .. SKIP + N).map(|n| {
+        let t = n as f64 * dt + 0.5;
+        gen.set_input(sync, t - t.floor());
+        gen.advance();
+        gen.get_output(0).unwrap()
+    }).skip(SKIP).collect()
+}
+
+fn additive_samples(typ: additive::Function, freq: f64) -> Vec<f64> {
+    let mut gen = additive::Gen::new(typ, Hz(freq), 10000);
+    (0 .. SKIP + N).map(|_| gen.advance()).skip(SKIP).collect()
+}
+
+fn wavetable_samples(typ: additive::Function, freq: f64) -> Vec<f64> {
+    let table = Table::from_arg(typ.name(), 2048).unwrap();
+    let mut gen = Wavetable::new(typ.name(), 2048, table, Hz(freq));
+    (0 .. SKIP + N).map(|_| gen.advance()).skip(SKIP).collect()
+}
+
+// notes, so that the aliases don't land on the harmonics
+const FREQS: [f64; 4] = [261.63, 1318.5, 2793.8, 5587.7];
+
+fn check_wave(name: &str, typ: simple::Function, add_typ: Option<additive::Function>, width: f64, all_harmonics: bool) {
+    println!("wave\tfreq\tnaive\tblep\tadditive\twavetable (dB of aliasing)");
+    for &freq in FREQS.iter() {
+        let naive = alias_db(&simple_samples(typ, freq, simple::Mode::Naive, width), freq, all_harmonics);
+        let blep = alias_db(&simple_samples(typ, freq, simple::Mode::Blep, width), freq, all_harmonics);
+        let add = add_typ.map(|t| alias_db(&additive_samples(t, freq), freq, all_harmonics));
+        let table = add_typ.map(|t| alias_db(&wavetable_samples(t, freq), freq, all_harmonics));
+        let show = |db: Option<f64>| db.map(|db| format!("{:.1}", db)).unwrap_or_else(|| "-".to_string());
+        println!("{}\t{}\t{:.1}\t{:.1}\t{}\t{}", name, freq, naive, blep, show(add), show(table));
+
+        // the corrections should take off most of the aliasing
+        // and the wavetables should hardly alias at all
+        assert!(blep < naive - 10.0, "{} at {}Hz: blep {:.1}dB, naive {:.1}dB", name, freq, blep, naive);
+        if let Some(db) = table {
+            assert!(db < -60.0, "{} wavetable at {}Hz: {:.1}dB", name, freq, db);
+        }
+    }
+}
+
+#[test]
+fn sawup_aliases_less() {
+    check_wave("sawup", simple::Function::SAWUP, Some(additive::Function::SAWUP), 0.5, true);
+}
+
+#[test]
+fn square_aliases_less() {
+    check_wave("square", simple::Function::SQUARE, Some(additive::Function::SQUARE), 0.5, false);
+}
+
+#[test]
+fn tri_aliases_less() {
+    check_wave("tri", simple::Function::TRI, Some(additive::Function::TRI), 0.5, false);
+}
+
+#[test]
+fn pulse_aliases_less() {
+    check_wave("pulse25", simple::Function::SQUARE, None, 0.25, true);
+}
+
+#[test]
+fn hard_sync_aliases_less() {
+    println!("wave\tfreq\tnaive\tblep\tadditive (dB of aliasing, synced)");
+    let waves = [
+        ("sawup", simple::Function::SAWUP, additive::Function::SAWUP),
+        ("square", simple::Function::SQUARE, additive::Function::SQUARE),
+    ];
+    for &(name, typ, add_typ) in waves.iter() {
+        for &freq in FREQS[.. 3].iter() {
+            let slave = |mode| {
+                let mut gen = simple::Gen::new(typ, Hz(freq * 2.37));
+                gen.set_mode(mode);
+                alias_db(&synced_samples(gen, freq, 2), freq, true)
+            };
+            let (naive, blep) = (slave(simple::Mode::Naive), slave(simple::Mode::Blep));
+            let add = alias_db(&synced_samples(additive::Gen::new(add_typ, Hz(freq * 2.37), 10000), freq, 1), freq, true);
+            println!("{}\t{}\t{:.1}\t{:.1}\t{:.1}", name, freq, naive, blep, add);
+
+            assert!(blep < naive - 10.0, "synced {} at {}Hz: blep {:.1}dB, naive {:.1}dB", name, freq, blep, naive);
+            assert!(add < naive - 10.0, "synced additive {} at {}Hz: {:.1}dB, naive {:.1}dB", name, freq, add, naive);
+        }
+    }
+}
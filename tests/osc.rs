@@ -0,0 +1,128 @@
+/*
+ * The OSC server, controlled by a client on localhost while the rack runs.
+ */
+
+use std::env;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use rau::loader::Loader;
+use rau::osc::{self, Arg, Client, Message, Server};
+use rau::units::Samples;
+
+const PATCH: &str = "src const 2
+m mult
+f filter lp 1kHz
+speaker speaker
+wire src:out m:in1
+wire m:out f:in
+wire f:out speaker:left
+wire f:out speaker:right
+";
+
+// Run the rack on this thread while the client talks to its server on another.
+// Panics in the client, such as failed asserts, fail the test.
+fn with_rack<F: FnOnce(&Client) + Send + 'static>(name: &str, f: F) {
+    let fname = format!("{}/rau_osc_{}.rau", env::temp_dir().to_string_lossy(), name);
+    fs::write(&fname, PATCH).unwrap();
+    let mut l = Loader::new();
+    l.capture_speakers();
+    let mut rack = l.load(&fname).unwrap();
+    let server = Server::bind("127.0.0.1:0").unwrap();
+    let client = Client::connect(server.local_addr().unwrap()).unwrap();
+
+    let handle = thread::spawn(move || f(&client));
+    while !handle.is_finished() {
+        rack.run(Samples(128));
+        server.poll(&mut rack);
+    }
+    handle.join().unwrap();
+}
+
+// Outputs only change as the rack runs, so give it a moment to get there.
+fn settled(c: &Client, mod_name: &str, out: &str, want: f64) {
+    let mut got = c.query(mod_name, out).unwrap();
+    for _ in 0 .. 100 {
+        if got == want {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+        got = c.query(mod_name, out).unwrap();
+    }
+    assert_eq!(got, want, "{}:{}", mod_name, out);
+}
+
+// A bundle holding a single message, nested depth times.
+fn nested_bundle(depth: usize, msg: &Message) -> Vec<u8> {
+    let mut buf = msg.encode();
+    for _ in 0 .. depth {
+        let mut outer = Vec::new();
+        outer.extend_from_slice(b"#bundle\0");
+        outer.extend_from_slice(&1u64.to_be_bytes());
+        outer.extend_from_slice(&(buf.len() as i32).to_be_bytes());
+        outer.extend_from_slice(&buf);
+        buf = outer;
+    }
+    buf
+}
+
+#[test]
+fn messages_decode_as_encoded() {
+    let msg = Message::new("/rack/x/y", vec![Arg::Int(-3), Arg::Float(0.5), Arg::Double(1e100), Arg::Str("abc".to_string())]);
+    assert_eq!(osc::decode(&msg.encode()).unwrap(), vec![msg.clone()]);
+    let msgs = vec![msg.clone(), Message::new("/rack/z", Vec::new())];
+    assert_eq!(osc::decode(&osc::encode_bundle(&msgs)).unwrap(), msgs);
+}
+
+#[test]
+fn deeply_nested_bundles_are_rejected() {
+    let msg = Message::new("/rack/m/in2", vec![Arg::Double(0.5)]);
+    assert_eq!(osc::decode(&nested_bundle(8, &msg)).unwrap(), vec![msg.clone()]);
+    let e = osc::decode(&nested_bundle(1000, &msg)).unwrap_err();
+    assert!(e.contains("nested"), "{}", e);
+}
+
+#[test]
+fn outputs_are_answered_without_rounding() {
+    with_rack("query", |c| {
+        assert_eq!(c.query("src", "out").unwrap(), 2.0);
+        c.set("m", "in2", 0.1).unwrap();
+        assert_eq!(c.query("m", "in2").unwrap(), 0.1);
+        c.send(&Message::new("/rack/m/in2", Vec::new())).unwrap();
+        assert_eq!(c.recv().unwrap().args, vec![Arg::Double(0.1)]);
+    });
+}
+
+#[test]
+fn inputs_are_set() {
+    with_rack("set", |c| {
+        c.set("m", "in2", 0.25).unwrap();
+        assert_eq!(c.query("m", "in2").unwrap(), 0.25);
+        settled(c, "m", "out", 0.5);
+    });
+}
+
+#[test]
+fn bundles_set_every_input() {
+    with_rack("bundle", |c| {
+        c.send_bundle(&[Message::new("/rack/m/in2", vec![Arg::Double(0.125)]), Message::new("/rack/f/freq", vec![Arg::Int(500)])]).unwrap();
+        assert_eq!(c.query("m", "in2").unwrap(), 0.125);
+        assert_eq!(c.query("f", "freq").unwrap(), 500.0);
+    });
+}
+
+#[test]
+fn problems_are_answered_with_errors() {
+    with_rack("errors", |c| {
+        c.set("f", "freq", 1e6).unwrap();
+        let e = c.recv().unwrap_err();
+        assert!(e.contains("between"), "{}", e);
+
+        let e = c.query("nope", "out").unwrap_err();
+        assert!(e.contains("nope"), "{}", e);
+
+        c.send(&Message::new("/other/thing", Vec::new())).unwrap();
+        let e = c.recv().unwrap_err();
+        assert!(e.contains("/rack/"), "{}", e);
+    });
+}
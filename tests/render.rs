@@ -0,0 +1,128 @@
+/*
+ * Rendering a patch offline and writing it to a wav file in each format,
+ * which reads back as what was rendered, and rendering at other sampling rates.
+ */
+
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use rau::{additive, render, simple};
+use rau::filt::{Filter, FiltType};
+use rau::loader::Loader;
+use rau::module::Module;
+use rau::units::{Hz, Samples};
+use rau::wav::{self, Sample, WavFormat};
+
+const PATCH: &str = "src const 0.5
+o osc2 sin 1000 0.8
+speaker speaker
+wire src:out speaker:left
+wire o:out speaker:right
+";
+
+const LEN: usize = 480;
+
+// Load a patch at a sampling rate and render it.
+fn render_at(name: &str, patch: &str, rate: f64) -> Result<Vec<Sample>, String> {
+    let fname = format!("{}/rau_render_{}.rau", env::temp_dir().to_string_lossy(), name);
+    fs::write(&fname, patch).unwrap();
+    let mut l = Loader::new();
+    l.set_sample_rate(rate);
+    let rec = l.capture_speakers();
+    let mut rack = l.load(&fname)?;
+    Ok(render::render(&mut rack, &rec, Samples(LEN)))
+}
+
+fn rendered() -> Vec<Sample> {
+    render_at("wav", PATCH, 48000.0).unwrap()
+}
+
+// The bytes of the data chunk, read without the wav crate.
+fn data_chunk(bytes: &[u8]) -> &[u8] {
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let size = u32::from_le_bytes(bytes[pos + 4 .. pos + 8].try_into().unwrap()) as usize;
+        if &bytes[pos .. pos + 4] == b"data" {
+            return &bytes[pos + 8 .. pos + 8 + size];
+        }
+        pos += 8 + size + size % 2;
+    }
+    panic!("no data chunk");
+}
+
+// Write the samples in a format and check what reads back, returning the raw data.
+fn round_trip(samps: &[Sample], fmt: WavFormat, tolerance: f64) -> Vec<u8> {
+    let fname = format!("{}/rau_render_{:?}.wav", env::temp_dir().to_string_lossy(), fmt);
+    wav::write_wav(&fname, 44100, samps, fmt).unwrap();
+    let (rate, back) = wav::read_wav(&fname);
+    assert_eq!(rate, 44100);
+    assert_eq!(back.len(), samps.len(), "{:?}", fmt);
+    for (n, (a, b)) in samps.iter().zip(back.iter()).enumerate() {
+        assert!((a.left - b.left).abs() <= tolerance, "{:?} left {}: {} {}", fmt, n, a.left, b.left);
+        assert!((a.right - b.right).abs() <= tolerance, "{:?} right {}: {} {}", fmt, n, a.right, b.right);
+    }
+    data_chunk(&fs::read(&fname).unwrap()).to_vec()
+}
+
+#[test]
+fn render_captures_the_speaker_inputs() {
+    let samps = rendered();
+    assert_eq!(samps.len(), LEN);
+    assert!(samps.iter().all(|s| s.left == 0.5));
+    assert!(samps.iter().all(|s| s.right.abs() <= 0.8));
+    let peak = samps.iter().map(|s| s.right.abs()).fold(0.0, f64::max);
+    assert!(peak > 0.79, "{}", peak);
+}
+
+#[test]
+fn s16_reads_back() {
+    let data = round_trip(&rendered(), WavFormat::S16, 2.0 / 32767.0);
+    assert_eq!(data.len(), LEN * 2 * 2);
+    // 0.5 * 32767, truncated
+    assert_eq!(&data[0 .. 2], &16383i16.to_le_bytes());
+}
+
+#[test]
+fn s24_reads_back() {
+    let data = round_trip(&rendered(), WavFormat::S24, 2.0 / 8388607.0);
+    assert_eq!(data.len(), LEN * 2 * 3);
+    // 0.5 * 8388607, truncated, in three little endian bytes
+    assert_eq!(&data[0 .. 3], &[0xff, 0xff, 0x3f]);
+}
+
+#[test]
+fn f32_reads_back() {
+    let samps = rendered();
+    let data = round_trip(&samps, WavFormat::F32, 1e-7);
+    assert_eq!(data.len(), LEN * 2 * 4);
+    assert_eq!(&data[4 .. 8], &(samps[0].right as f32).to_le_bytes());
+}
+
+#[test]
+fn frequencies_above_nyquist_are_rejected() {
+    for def in ["filter lp 23000", "osc2 sin 23000", "osc sawup 23000 8", "fmop 23000", "wavetable sawup 23000", "sh random 23000"] {
+        let patch = format!("x {}\nspeaker speaker\nwire x:out speaker:left\n", def);
+        let e = render_at("nyquist", &patch, 44100.0).unwrap_err();
+        assert!(e.contains("22050"), "{}: {}", def, e);
+        assert!(render_at("nyquist", &patch, 48000.0).is_ok(), "{}", def);
+    }
+}
+
+// A module made at the default rate can be above nyquist once the rack's rate is lower.
+#[test]
+fn modules_moved_to_a_lower_rate_stay_at_nyquist() {
+    let mut f = Filter::new(FiltType::LP, Hz(23000.0), 0.0, 0.7);
+    f.set_sample_rate(44100.0);
+    f.set_input(0, 1.0);
+    for _ in 0 .. LEN {
+        f.advance();
+        assert!(f.get_output(0).unwrap().is_finite());
+    }
+
+    let mut o = simple::Gen::new(simple::Function::SIN, Hz(23000.0));
+    Module::set_sample_rate(&mut o, 44100.0);
+    assert_eq!(o.get_input(0), Some(22050.0));
+    let mut a = additive::Gen::new(additive::Function::SIN, Hz(23000.0), 8);
+    Module::set_sample_rate(&mut a, 44100.0);
+    assert_eq!(a.get_input(0), Some(22050.0));
+}
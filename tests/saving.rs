@@ -0,0 +1,154 @@
+/*
+ * Racks saved as config text load back into equivalent racks,
+ * for every registered module type.
+ */
+
+use std::env;
+use std::fs;
+use std::panic;
+use rau::loader::Loader;
+use rau::module::*;
+use rau::units::Samples;
+
+// A small patch for each module type, named m, with some inputs set away from their defaults.
+const EXAMPLES: &[(&str, &str)] = &[
+    ("add", "m add\nset m:in1 0.25\nset m:in2 0.5\n"),
+    ("bias", "src osc2 sin 3\nm bias 100 50\nwire src:out m:in\n"),
+    ("const", "m const 440\n"),
+    ("delay", "src osc2 sawup 220\nm delay 20ms 0.5 0.3\nwire src:out m:in\nset m:delay 10ms\n"),
+    ("envelope", "m envelope 10ms 50ms 0.5 100ms\nset m:gate 1\n"),
+    ("file", "src osc2 sin 440\nm file $tmp/rau_saving.s16\nwire src:out m:in\n"),
+    ("filter", "src osc2 sawup 110\nm filter type=bp freq=1.2kHz gain=-3dB q=2\nwire src:out m:in\n"),
+    ("flange", "src osc sawup 220 16\nm flange tri 0.5 0.4 -0.9 0.3\nwire src:out m:in\nset m:freq 2\n"),
+    ("fmop", "src fmop 3\nm fmop 440 2 0.5\nwire src:out m:pm *2\nset m:fm -100\n"),
+    ("fmvoice", "g osc2 square 5 amp=0.5 off=0.5\nm fmvoice 4 ratios=1,2,3,1,2,14 decay=100ms,200ms,300ms,400ms,500ms,600ms\nwire g:out m:gate\n"),
+    ("inv", "src osc2 square 100\nm inv\nwire src:out m:in\n"),
+    ("keyboard", "m keyboard 10ms\n"),
+    ("midifile", "m midifile configs/midi/scale.mid loop 1\n"),
+    ("mix", "a osc2 sin 3\nb osc2 sawup 5\nm mix\nwire a:out m:in\nwire b:out m:in *0.5\n"),
+    ("mult", "src osc2 sin 5\nm mult\nwire src:out m:in1\nset m:in2 0.5\n"),
+    ("noise", "m noise pink seed=5\n"),
+    ("osc", "m osc square 220 8\nset m:freq A4\nset m:phase 0.1\nclk osc2 square 30 amp=0.5 off=0.5\nwire clk:out m:sync\n"),
+    ("osc2", "m osc2 square 300 amp=0.5 off=0.5 mode=blep\nset m:width 0.3\nset m:phase 0.25\nclk osc2 sawup 70\nwire clk:eoc m:sync\n"),
+    ("phaser", "src osc sawup 220 16\nm phaser sin 1 -0.8 0.2\nwire src:out m:in\n"),
+    ("poly", "define voice\no osc2 sin 220\ne envelope 10ms 50ms 0.5 100ms\na mult\nwire o:out a:in1\nwire e:out a:in2\n\
+        input freq o:freq\ninput gate e:gate\noutput out a:out\nend\nsong midifile configs/midi/scale.mid loop\nm poly voice 3 roundrobin\nwire song:notes2 m:notes\n"),
+    ("pitchcorrect", "src osc sawup 230 16\nm pitchcorrect 100 1000 0.5\nwire src:out m:in\n"),
+    ("voices", "song midifile configs/midi/scale.mid loop\nm voices 2 softest\nwire song:notes2 m:notes\n"),
+    ("wavetable", "m wavetable 1:1,2:0.5/1:1,3:0.3 440 64\nset m:position 0.25\n"),
+    ("sh", "n noise brown\nclk osc2 square 100 amp=0.5 off=0.5\nm sh sample\nwire n:out m:in\nwire clk:out m:trig\ns sh smooth 200 seed=9\n"),
+    ("speaker", "src osc2 sin 440\nsrc2 osc2 sin 660\nm speaker\nwire src:out m:left *0.5\nwire src2:out m:left *0.25 -0.1\nset m:right 0.1\n"),
+];
+
+const RUN_SAMPLES: usize = 4800;
+
+fn load_text(text: &str, fname: &str) -> Result<(Rack, rau::render::Recording), String> {
+    fs::write(fname, text).map_err(|e| format!("{}: {}", fname, e))?;
+    let mut l = Loader::new();
+    let rec = l.capture_speakers();
+    let rack = l.load(fname)?;
+    Ok((rack, rec))
+}
+
+fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0)
+}
+
+// Config texts match if they only differ by rounding in their numbers.
+fn same_text(a: &str, b: &str) -> bool {
+    let wa: Vec<&str> = a.split_whitespace().collect();
+    let wb: Vec<&str> = b.split_whitespace().collect();
+    wa.len() == wb.len() && wa.iter().zip(wb.iter()).all(|(x, y)|
+        x == y || matches!((x.parse::<f64>(), y.parse::<f64>()), (Ok(x), Ok(y)) if close(x, y)))
+}
+
+fn check(patch: &str, tmp: &str) -> Result<(), String> {
+    let (mut rack1, rec1) = load_text(patch, &format!("{}/rau_saving1.rau", tmp))?;
+    let saved = rack1.to_rau();
+    let (mut rack2, rec2) = load_text(&saved, &format!("{}/rau_saving2.rau", tmp))
+        .map_err(|e| format!("{}\nsaved text:\n{}", e, saved))?;
+    let resaved = rack2.to_rau();
+    if !same_text(&saved, &resaved) {
+        return Err(format!("saved text changed on reload:\n{}\nbecame:\n{}", saved, resaved));
+    }
+
+    // look the modules and their outputs up once, the racks are run for a while
+    let mut mods = Vec::new();
+    for name in rack1.module_names() {
+        mods.push((name.to_string(), rack1.module(name)?, rack2.module(name)?, rack1.get_terminals(name)?.1));
+    }
+    for n in 0 .. RUN_SAMPLES {
+        rack1.run(Samples(1));
+        rack2.run(Samples(1));
+        for (name, m1, m2, outs) in mods.iter() {
+            let (m1, m2) = (m1.borrow(), m2.borrow());
+            for (idx, out) in outs.iter().enumerate() {
+                if out.unit == Unit::Event {
+                    let (e1, e2) = (m1.get_events(idx), m2.get_events(idx));
+                    if e1 != e2 {
+                        return Err(format!("{}:{} differs at sample {}: {:?} vs {:?}", name, out.name, n, e1, e2));
+                    }
+                    continue;
+                }
+                let v1 = m1.get_output(idx).unwrap_or(0.0);
+                let v2 = m2.get_output(idx).unwrap_or(0.0);
+                if !close(v1, v2) {
+                    return Err(format!("{}:{} differs at sample {}: {} vs {}", name, out.name, n, v1, v2));
+                }
+            }
+        }
+    }
+
+    let (rec1, rec2) = (rec1.borrow(), rec2.borrow());
+    if rec1.len() != rec2.len() || rec1.iter().zip(rec2.iter()).any(|(a, b)| !close(a.left, b.left) || !close(a.right, b.right)) {
+        return Err("speaker output differs".to_string());
+    }
+    Ok(())
+}
+
+// A module's terminals have the names and units its descriptor gives them.
+fn check_terminals(descr: &ModuleDescr, patch: &str, tmp: &str) -> Result<(), String> {
+    let (rack, _) = load_text(patch, &format!("{}/rau_saving1.rau", tmp))?;
+    if !rack.has_module("m") {
+        return Ok(()); // made of other modules, such as poly
+    }
+    let (ins, outs) = rack.get_terminals("m")?;
+    let same = |a: &[TerminalDescr], b: &[TerminalDescr]|
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.name == y.name && x.unit == y.unit);
+    if !same(&ins, &descr.inputs) || !same(&outs, &descr.outputs) {
+        return Err("terminals don't match the descriptor".to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn every_module_loads_back_from_saved_text() {
+    let tmp = env::temp_dir().to_string_lossy().to_string();
+    let l = Loader::new();
+    let mut failures = Vec::new();
+
+    for descr in l.descriptors() {
+        let patch = match EXAMPLES.iter().find(|(name, _)| *name == descr.name) {
+            Some((_, patch)) => patch.replace("$tmp", &tmp),
+            None => {
+                failures.push(format!("{}: no example patch", descr.name));
+                continue;
+            },
+        };
+
+        // the keyboard needs a display, which tests may not have
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let res = panic::catch_unwind(|| check_terminals(descr, &patch, &tmp).and_then(|_| check(&patch, &tmp)));
+        panic::set_hook(hook);
+
+        match res {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => failures.push(format!("{}: {}", descr.name, e)),
+            Err(_) if descr.name == "keyboard" => (),
+            Err(_) => failures.push(format!("{}: panicked", descr.name)),
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}